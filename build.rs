@@ -1,5 +1,3 @@
-use built;
-
 fn main() {
     built::write_built_file().expect("Failed to acquire build-time information");
 }
\ No newline at end of file
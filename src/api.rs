@@ -1,45 +1,28 @@
+use std::sync::Arc;
+
 use log::{debug, error, info};
 use poem::IntoResponse;
 use poem_openapi::{
     param::{Path, Query},
-    payload::{Json, Payload},
+    payload::{Json, Payload, PlainText},
     ApiResponse, OpenApi,
 };
-use reqwest::Client;
+use uuid::Uuid;
 
 use crate::{
-    data::{Alert, AlertLevel, ApiServices, Blockdevice, Smart},
-    smart, truenas, Config,
+    auth, data::{Alert, AlertLevel, ApiServices, AttributeHistory, Blockdevice, DrivePrediction, NotificationResult, Pool, SelfTestStatus, Smart, TruenasDisk, TruenasStatus},
+    collector::{self, Collector},
+    metrics, settings::{Settings, SharedSettings}, smart::{self, SelfTestReading, SmartReading}, truenas, zfs,
 };
 
 pub struct Api {
-    config: Config,
     smart_enabled: bool,
-    truenas_enabled: bool,
-    client: Client,
+    zfs_enabled: bool,
+    settings: SharedSettings,
+    collector: Arc<Collector>,
 }
 
-pub fn new_api(config: Config) -> Api {
-    fn is_truenas(config: &Config) -> (bool, Client) {
-        if config.use_truenas {
-            if let (Some(token), Some(address)) = (&config.truenas_token, &config.truenas_address) {
-                if !token.is_empty() && !address.cannot_be_a_base() {
-                    if let Some(client) = truenas::get_client(config.accept_invalid_certs) {
-                        info!("TrueNAS support enabled!");
-                        return (true, client);
-                    }
-                    error!("Failed to create Web Client, no TrueNAS support");
-                    return (false, reqwest::Client::new());
-                }
-            }
-            error!("No Address and or Token provided, no TrueNAS support")
-        }
-
-        (false, reqwest::Client::new())
-    }
-
-    let (truenas_enabled, client) = is_truenas(&config);
-
+pub fn new_api(settings: SharedSettings) -> Api {
     let smart_enabled = if cfg!(target_os = "linux") {
         if nix::unistd::Uid::effective().is_root() {
             info!("Smart support enabled");
@@ -51,11 +34,20 @@ pub fn new_api(config: Config) -> Api {
         false
     };
 
+    let zfs_enabled = zfs::is_available();
+    if zfs_enabled {
+        info!("ZFS support enabled");
+    }
+
+    let collector = collector::new_collector(settings.clone(), smart_enabled);
+    collector.spawn();
+    collector.spawn_alert_watch();
+
     Api {
-        config,
-        truenas_enabled,
         smart_enabled,
-        client,
+        zfs_enabled,
+        settings,
+        collector,
     }
 }
 
@@ -77,6 +69,161 @@ where
     /// This methode is part of a service that is disabled, please check the server config and /services to enable this methode
     #[oai(status = 503)]
     ServiceDisabled,
+    /// The drive is asleep and was not read since the server started, as this would wake it up
+    #[oai(status = 409)]
+    DriveAsleep,
+    /// This methode is only available if an api key with the action scope is set in the server config
+    #[oai(status = 403)]
+    Forbidden,
+    /// The drive does not support this methode (like the self-test log of SCSI drives)
+    #[oai(status = 501)]
+    NotSupported,
+}
+
+impl Api {
+    /// Checks the drive name against the disk list, so no user input is passed on to smartctl
+    async fn sanitize_drive<T>(&self, drive: &str) -> Result<String, RdmResponde<T>>
+    where
+        T: Payload,
+        T: IntoResponse,
+    {
+        if let Some(disks) = self.collector.disks(false).await {
+            for item in disks {
+                if item.name == drive {
+                    return Ok(item.name);
+                }
+            }
+
+            return Err(RdmResponde::NotFound);
+        }
+
+        Err(RdmResponde::InternalServerError)
+    }
+
+    async fn smart_reader(&self, drive: String, extended: bool, refresh: bool) -> RdmResponde<Json<Smart>> {
+        if !self.smart_enabled {
+            return RdmResponde::ServiceDisabled;
+        }
+
+        match self.collector.smart(drive, refresh).await {
+            Some(SmartReading::Data(mut info)) => {
+                // The collector always reads the extended data
+                if !extended {
+                    info.error_log = None;
+                    info.self_tests = None;
+                    if let Some(temp) = info.temperature.as_mut() {
+                        temp.history = None;
+                    }
+                }
+
+                RdmResponde::Ok(Json(*info))
+            },
+            Some(SmartReading::Asleep(_)) => RdmResponde::DriveAsleep,
+            None => RdmResponde::InternalServerError
+        }
+    }
+
+    /// Returns the names of the TrueNAS instances matching the `instance` filter, all if it is not set
+    fn select_instances<T>(&self, settings: &Settings, instance: Option<String>) -> Result<Vec<String>, RdmResponde<T>>
+    where
+        T: Payload,
+        T: IntoResponse,
+    {
+        if !settings.truenas_enabled() {
+            return Err(RdmResponde::ServiceDisabled);
+        }
+
+        match instance {
+            Some(name) if settings.truenas_instance(&name).is_none() => Err(RdmResponde::NotFound),
+            Some(name) => Ok(vec![name]),
+            None => Ok(settings.truenas_instances().iter().map(|instance| instance.name.clone()).collect())
+        }
+    }
+
+    /// Reads the pools and disks from TrueNAS, and links them with the disks of this machine
+    ///
+    /// Instances that could not be read are left out, unless none could be read
+    async fn truenas_storage<T>(&self, instance: Option<String>) -> Result<(Vec<Pool>, Vec<TruenasDisk>), RdmResponde<T>>
+    where
+        T: Payload,
+        T: IntoResponse,
+    {
+        let settings = self.settings.load_full();
+        let names = self.select_instances(&settings, instance)?;
+        let host = self.collector.disks(false).await.unwrap_or_default();
+
+        let mut result: Option<(Vec<Pool>, Vec<TruenasDisk>)> = None;
+        for instance in names.iter().filter_map(|name| settings.truenas_instance(name)) {
+            let (Some(mut pools), Some(mut disks)) = (truenas::get_pools(instance).await, truenas::get_disks(instance).await) else {
+                error!("Failed to read the pools of TrueNAS {}", instance.name);
+                continue;
+            };
+
+            // Disk names are only unique within an instance
+            truenas::link_disks(&mut pools, &mut disks, &host);
+
+            let (all_pools, all_disks) = result.get_or_insert((Vec::new(), Vec::new()));
+            all_pools.append(&mut pools);
+            all_disks.append(&mut disks);
+        }
+
+        result.ok_or(RdmResponde::InternalServerError)
+    }
+
+    /// Dismisses or restores the alert in TrueNAS, so the TrueNAS key does not have to be handed out for this
+    async fn dismiss_alert(&self, uuid: Uuid, dismiss: bool) -> RdmResponde<Json<Alert>> {
+        let settings = self.settings.load_full();
+        if !settings.truenas_enabled() {
+            return RdmResponde::ServiceDisabled;
+        }
+        // Without keys anyone on the network could use the TrueNAS token
+        if !auth::has_action_key(&settings.config.api_keys) {
+            return RdmResponde::Forbidden;
+        }
+
+        let alerts = self.collector.read_alerts().await;
+        if alerts.iter().all(|(_, alerts)| alerts.is_none()) {
+            return RdmResponde::InternalServerError;
+        }
+        let Some(mut alert) = alerts.into_iter().flat_map(|(_, alerts)| alerts.unwrap_or_default()).find(|alert| alert.uuid == uuid) else {
+            return RdmResponde::NotFound;
+        };
+        let Some(instance) = settings.truenas_instance(&alert.instance) else {
+            return RdmResponde::InternalServerError;
+        };
+
+        if !truenas::dismiss_alert(instance, uuid, dismiss).await {
+            error!("TrueNAS did not {} alert {}", if dismiss { "dismiss" } else { "restore" }, uuid);
+            return RdmResponde::InternalServerError;
+        }
+
+        info!("{} alert {}", if dismiss { "Dismissed" } else { "Restored" }, uuid);
+        self.collector.set_dismissed(uuid, dismiss).await;
+        alert.dismissed = dismiss;
+
+        RdmResponde::Ok(Json(alert))
+    }
+
+    async fn self_test_reader(&self, drive: String) -> RdmResponde<Json<SelfTestStatus>> {
+        if !self.smart_enabled {
+            return RdmResponde::ServiceDisabled;
+        }
+
+        let standby_check = self.settings.load().config.standby_check;
+        match tokio::task::spawn_blocking(move || smart::get_self_test_status(drive, standby_check)).await.ok().flatten() {
+            Some(SelfTestReading::Status(status)) => RdmResponde::Ok(Json(status)),
+            // A drive that is asleep is not running a self-test
+            Some(SelfTestReading::Asleep(state)) => RdmResponde::Ok(Json(SelfTestStatus {
+                running: false,
+                progress_percent: None,
+                power_state: Some(state),
+                status: format!("{:?}", state).to_lowercase(),
+                results: Vec::new()
+            })),
+            Some(SelfTestReading::Unsupported) => RdmResponde::NotSupported,
+            None => RdmResponde::InternalServerError
+        }
+    }
 }
 
 #[OpenApi]
@@ -91,39 +238,60 @@ impl Api {
     /// Returns the Services available with on this server
     ///
     /// This function serves to debug the configuartion, as certain functions require certain services to work.<br>
-    /// The state of the "_enabled" values won't change till a restart of the server, but the `truenas_status` is however an active connection test.<br>  
+    /// The state of the "_enabled" values only changes with a restart or config reload (SIGHUP), but the `truenas_status` is however an active connection test.<br>  
     /// Although false values there could also indicate improper configuartion (like incorrect token or server address).<br>
-    /// And if `truenas_enabled` is false it will always be false.<br>
-    /// `smart_enabled` is false when you are runnning without root (or not on a linux system)
+    /// And if `truenas_enabled` is false it will always be false. With several TrueNAS instances it is only true if all answered, `truenas_instances` has the result of each.<br>
+    /// `smart_enabled` is false when you are runnning without root (or not on a linux system)<br>
+    /// `zfs_enabled` is false when zpool is not installed
     #[oai(path = "/services", method = "get")]
     pub async fn get_services(&self) -> Json<ApiServices> {
+        let settings = self.settings.load_full();
+
+        let status = truenas::ping_all(settings.truenas_instances()).await;
+        let instances: Vec<TruenasStatus> = settings.truenas_instances().iter().zip(status).map(|(instance, status)| {
+            TruenasStatus {
+                name: instance.name.clone(),
+                status
+            }
+        }).collect();
+
         Json(ApiServices {
-            truenas_enabled: self.truenas_enabled,
+            truenas_enabled: settings.truenas_enabled(),
             smart_enabled: self.smart_enabled,
-            truenas_status: if self.truenas_enabled {
-                truenas::do_ping(
-                    &self.client,
-                    self.config
-                        .truenas_address
-                        .as_ref()
-                        .expect("When truenas is enabled the address has to be set"),
-                    self.config
-                        .truenas_token
-                        .as_ref()
-                        .expect("When truenas is enabled the token has to be set"),
-                )
-                .await
-                .unwrap_or(false)
-            } else {
-                false
-            },
+            truenas_status: !instances.is_empty() && instances.iter().all(|instance| instance.status),
+            truenas_instances: instances,
+            history_enabled: self.collector.history_enabled(),
+            zfs_enabled: self.zfs_enabled,
         })
     }
 
+    /// Returns all data in the Prometheus text exposition format
+    ///
+    /// Contains the smart data of all drives from the last background poll, labeled with name, disk_id, serial and model.<br>
+    /// When truenas_enabled it also contains `rdm_truenas_status` and the number of alerts per level, labeled with the name of the instance as `truenas`.<br>
+    /// All instances are pinged at once, one that does not answer within 2 seconds counts as down
+    #[oai(path = "/metrics", method = "get")]
+    pub async fn get_metrics(&self) -> PlainText<String> {
+        let disks = self.collector.disks(false).await.unwrap_or_default();
+        let smart = if self.smart_enabled { self.collector.all_smart().await } else { Vec::new() };
+
+        let settings = self.settings.load_full();
+        // Alerts are only read from the instances that answered, so one that hangs does not stall the scrape
+        let status = truenas::ping_all(settings.truenas_instances()).await;
+        let truenas = futures_util::future::join_all(settings.truenas_instances().iter().zip(status).map(|(instance, status)| async move {
+            let alerts = if status { self.collector.read_instance_alerts(instance).await } else { None };
+            (instance.name.clone(), status, alerts)
+        })).await;
+
+        PlainText(metrics::render(&disks, &smart, &truenas))
+    }
+
     /// Returns all the disks
+    ///
+    /// * `refresh` - read the disks now instead of returning the result of the last background poll (default false)
     #[oai(path = "/drivelist", method = "get")]
-    pub async fn get_drive_list(&self) -> RdmResponde<Json<Vec<Blockdevice>>> {
-        if let Some(disks) = smart::get_disks() {
+    pub async fn get_drive_list(&self, refresh: Query<Option<bool>>) -> RdmResponde<Json<Vec<Blockdevice>>> {
+        if let Some(disks) = self.collector.disks(refresh.0.unwrap_or(false)).await {
             return RdmResponde::Ok(Json(disks));
         }
 
@@ -135,20 +303,188 @@ impl Api {
     /// This function requires smart_enabled, check `/services`  
     ///
     /// * `drive` - name of the drive, for example "sda"
+    /// * `extended` - also include the error, self-test and temperature history logs (default false)
+    /// * `refresh` - read the drive now instead of returning the result of the last background poll (default false)
     #[oai(path = "/smart/:drive", method = "get")]
-    pub async fn get_smart_data(&self, drive: Path<String>) -> RdmResponde<Json<Smart>> {
-        if let Some(disks) = smart::get_disks() {
-            // Sanetize input
-            for item in disks {
-                if item.name == drive.clone() {
-                    return self.smart_reader(item.name);
-                }
-            }
+    pub async fn get_smart_data(&self, drive: Path<String>, extended: Query<Option<bool>>, refresh: Query<Option<bool>>) -> RdmResponde<Json<Smart>> {
+        match self.sanitize_drive(&drive).await {
+            Ok(name) => self.smart_reader(name, extended.0.unwrap_or(false), refresh.0.unwrap_or(false)).await,
+            Err(e) => e
+        }
+    }
+
+    /// Returns the stored history of a Smart Attribute of a certain drive via simple name
+    ///
+    /// This function requires history_enabled, check `/services`  
+    /// Only changes are stored, so each point is valid until the next one.  
+    /// The first point is the value the attribute had at `since`
+    ///
+    /// * `drive` - name of the drive, for example "sda"
+    /// * `attribute` - id of the Ata attribute (in decimal), or the name of a health value like temperature, media_errors, percentage_used, grown_defect_list
+    /// * `since` - unix time in ms (default 0)
+    #[oai(path = "/smart/:drive/history", method = "get")]
+    pub async fn get_smart_history(&self, drive: Path<String>, attribute: Query<String>, since: Query<Option<u64>>) -> RdmResponde<Json<AttributeHistory>> {
+        let name = match self.sanitize_drive(&drive).await {
+            Ok(name) => name,
+            Err(e) => return e
+        };
+
+        if !self.collector.history_enabled() {
+            return RdmResponde::ServiceDisabled;
+        }
+
+        match self.collector.history(name, attribute.0, since.0.unwrap_or(0)).await {
+            Some(history) => RdmResponde::Ok(Json(history)),
+            None => RdmResponde::NotFound
+        }
+    }
+
+    /// Returns the trend evaluation of all drives
+    ///
+    /// This function requires history_enabled, check `/services`  
+    /// Drives without enough history are left out, the same data is part of `/smart/[drive]` as `prediction`
+    #[oai(path = "/predictions", method = "get")]
+    pub async fn get_predictions(&self) -> RdmResponde<Json<Vec<DrivePrediction>>> {
+        if !self.collector.history_enabled() {
+            return RdmResponde::ServiceDisabled;
+        }
+
+        RdmResponde::Ok(Json(self.collector.predictions().await))
+    }
 
+    /// Starts a Smart self-test on a certain drive via simple name
+    ///
+    /// This function requires smart_enabled, check `/services`  
+    /// Returns the self-test status after the test was started, 501 for drives without a readable self-test log (like SCSI drives)
+    ///
+    /// * `drive` - name of the drive, for example "sda"
+    /// * `type` - type of the self-test, can be either short, long, conveyance (default short)
+    #[oai(path = "/smart/:drive/selftest", method = "post")]
+    pub async fn post_self_test(&self, drive: Path<String>, #[oai(name = "type")] test_type: Query<Option<String>>) -> RdmResponde<Json<SelfTestStatus>> {
+        let name = match self.sanitize_drive(&drive).await {
+            Ok(name) => name,
+            Err(e) => return e
+        };
+
+        if !self.smart_enabled {
+            return RdmResponde::ServiceDisabled;
+        }
+
+        let test_type = test_type.0.unwrap_or("short".to_string()).to_lowercase();
+        if !matches!(test_type.as_str(), "short" | "long" | "conveyance") {
             return RdmResponde::NotFound;
         }
 
-        RdmResponde::InternalServerError
+        // Only start tests we can report the progress of
+        if let RdmResponde::NotSupported = self.self_test_reader(name.clone()).await {
+            return RdmResponde::NotSupported;
+        }
+
+        let drive = name.clone();
+        let kind = test_type.clone();
+        match tokio::task::spawn_blocking(move || smart::start_self_test(drive, &kind)).await.ok().flatten() {
+            Some(true) => info!("Started {} self-test on {}", test_type, name),
+            Some(false) => {
+                error!("Drive {} did not accept the {} self-test", name, test_type);
+                return RdmResponde::InternalServerError;
+            },
+            None => return RdmResponde::InternalServerError
+        }
+
+        self.self_test_reader(name).await
+    }
+
+    /// Returns the progress of a running Smart self-test and the results of previous ones
+    ///
+    /// This function requires smart_enabled, check `/services`  
+    /// Drives that are asleep (see `standby_check` in the config) are not woken up, instead `power_state` is set and no results are returned  
+    /// SCSI drives report their self-tests in a format that is not read, they return 501
+    ///
+    /// * `drive` - name of the drive, for example "sda"
+    #[oai(path = "/smart/:drive/selftest", method = "get")]
+    pub async fn get_self_test(&self, drive: Path<String>) -> RdmResponde<Json<SelfTestStatus>> {
+        match self.sanitize_drive(&drive).await {
+            Ok(name) => self.self_test_reader(name).await,
+            Err(e) => e
+        }
+    }
+
+    /// Returns the status of the local ZFS pools
+    ///
+    /// This function requires zfs_enabled, check `/services`  
+    /// Contains the vdev tree with the read, write and checksum errors of every device, the last scrub or resilver, fragmentation and capacity.<br>
+    /// With zpool older than OpenZFS 2.3 the text output is parsed, there the scan has no times but its `description`, and vdevs have no `type` or `path`
+    #[oai(path = "/pools", method = "get")]
+    pub async fn get_pools(&self) -> RdmResponde<Json<Vec<Pool>>> {
+        if !self.zfs_enabled {
+            return RdmResponde::ServiceDisabled;
+        }
+
+        match tokio::task::spawn_blocking(zfs::get_pools).await {
+            Ok(Some(pools)) => RdmResponde::Ok(Json(pools)),
+            _ => RdmResponde::InternalServerError
+        }
+    }
+
+    /// Returns the pools of TrueNAS
+    ///
+    /// This function requires truenas_enabled, check `/services`  
+    /// Devices carry the `serial` of their disk, and `host_disk` is the drive on this machine with the same serial (for disks passed through to the TrueNAS VM)<br>
+    /// Instances that could not be read are left out, check `/services`
+    ///
+    /// * `instance` - only the pools of this TrueNAS instance
+    #[oai(path = "/truenas/pools", method = "get")]
+    pub async fn get_truenas_pools(&self, instance: Query<Option<String>>) -> RdmResponde<Json<Vec<Pool>>> {
+        match self.truenas_storage(instance.0).await {
+            Ok((pools, _)) => RdmResponde::Ok(Json(pools)),
+            Err(e) => e
+        }
+    }
+
+    /// Returns the disks of TrueNAS
+    ///
+    /// This function requires truenas_enabled, check `/services`  
+    /// Each disk has the `pool` it is part of, and `host_disk` is the drive on this machine with the same serial<br>
+    /// Instances that could not be read are left out, check `/services`
+    ///
+    /// * `instance` - only the disks of this TrueNAS instance
+    #[oai(path = "/truenas/disks", method = "get")]
+    pub async fn get_truenas_disks(&self, instance: Query<Option<String>>) -> RdmResponde<Json<Vec<TruenasDisk>>> {
+        match self.truenas_storage(instance.0).await {
+            Ok((_, disks)) => RdmResponde::Ok(Json(disks)),
+            Err(e) => e
+        }
+    }
+
+    /// Sends a sample notification to all configured targets
+    ///
+    /// Returns for each target if it accepted the notification, failed webhooks are retried before this returns
+    #[oai(path = "/notifications/test", method = "post")]
+    pub async fn post_test_notification(&self) -> RdmResponde<Json<Vec<NotificationResult>>> {
+        let settings = self.settings.load_full();
+        let notifier = &settings.notifier;
+        if !notifier.enabled() {
+            return RdmResponde::ServiceDisabled;
+        }
+
+        RdmResponde::Ok(Json(notifier.test().await))
+    }
+
+    /// Sends the digest with the health of all drives to all email targets now
+    ///
+    /// Returns for each email target if the mail was accepted by the server
+    #[oai(path = "/notifications/digest", method = "post")]
+    pub async fn post_digest(&self) -> RdmResponde<Json<Vec<NotificationResult>>> {
+        let settings = self.settings.load_full();
+        let notifier = &settings.notifier;
+        if !notifier.enabled() {
+            return RdmResponde::ServiceDisabled;
+        }
+
+        let disks = self.collector.disks(false).await.unwrap_or_default();
+        let smart = if self.smart_enabled { self.collector.all_smart().await } else { Vec::new() };
+
+        RdmResponde::Ok(Json(notifier.digest_now(&disks, &smart).await))
     }
 
     /// Returns Smart Data for a certain drive based on disk-id
@@ -156,13 +492,19 @@ impl Api {
     /// This function requires smart_enabled, check `/services`
     ///
     /// * `drive` - disk-id of the drive (defined by /dev/disk/by-id/ on the machine), which you can retrieve via [`/drivelist`](crate::api::Api::get_drive_list)
+    /// * `extended` - also include the error, self-test and temperature history logs (default false)
+    /// * `refresh` - read the drive now instead of returning the result of the last background poll (default false)
     #[oai(path = "/smart/disk/by-id/:drive", method = "get")]
-    pub async fn get_smart_data_by_id(&self, drive: Path<String>) -> RdmResponde<Json<Smart>> {
+    pub async fn get_smart_data_by_id(&self, drive: Path<String>, extended: Query<Option<bool>>, refresh: Query<Option<bool>>) -> RdmResponde<Json<Smart>> {
         if let Some(disks) = smart::get_drive_id_list() {
             // Sanetize input
-            for (id, _target) in disks {
+            for (id, target) in disks {
                 if id == drive.clone() {
-                    return self.smart_reader(format!("disk/by-id/{}", id).to_string());
+                    // The cache is by drive name, so we resolve the link
+                    if let Some(name) = target.strip_prefix("/dev/") {
+                        return self.smart_reader(name.to_string(), extended.0.unwrap_or(false), refresh.0.unwrap_or(false)).await;
+                    }
+                    return RdmResponde::InternalServerError;
                 }
             }
 
@@ -172,29 +514,46 @@ impl Api {
         RdmResponde::InternalServerError
     }
 
-    fn smart_reader(&self, drive: String) -> RdmResponde<Json<Smart>> {
-        if !self.smart_enabled {
-            return RdmResponde::ServiceDisabled;
-        }
-
-        if let Some(info) = smart::get_smart(drive) {
-            return RdmResponde::Ok(Json(info));
-        }
+    /// Dismisses a TrueNAS alert
+    ///
+    /// This function requires truenas_enabled, check `/services`, and an api key with the action scope,
+    /// without any action key in the config it is not available at all  
+    /// Returns the alert after it was dismissed
+    ///
+    /// * `uuid` - uuid of the alert, from `/alerts`
+    #[oai(path = "/alerts/:uuid/dismiss", method = "post")]
+    pub async fn post_dismiss_alert(&self, uuid: Path<Uuid>) -> RdmResponde<Json<Alert>> {
+        self.dismiss_alert(uuid.0, true).await
+    }
 
-        RdmResponde::InternalServerError
+    /// Restores a dismissed TrueNAS alert
+    ///
+    /// This function requires truenas_enabled, check `/services`, and an api key with the action scope,
+    /// without any action key in the config it is not available at all  
+    /// Returns the alert after it was restored
+    ///
+    /// * `uuid` - uuid of the alert, from `/alerts?include_dismissed=true`
+    #[oai(path = "/alerts/:uuid/restore", method = "post")]
+    pub async fn post_restore_alert(&self, uuid: Path<Uuid>) -> RdmResponde<Json<Alert>> {
+        self.dismiss_alert(uuid.0, false).await
     }
 
     /// Returns all current alerts
     /// 
-    /// This function requires truenas_enabled, check `/services`
+    /// This function requires truenas_enabled, check `/services`  
+    /// While the websocket to TrueNAS is connected the alerts are served from the cache, which it keeps up to date<br>
+    /// Instances that could not be read are left out, check `/services`
     /// 
     /// * `level` - minimum alert level, can be either Info, Warning, Critical
     /// * `include_dismissed` - include also dismissed alerts (per default they are ignored)
+    /// * `instance` - only the alerts of this TrueNAS instance
     #[oai(path = "/alerts", method = "get")]
-    pub async fn get_alerts_on_level(&self, level: Query<Option<String>>, include_dismissed: Query<Option<bool>>) -> RdmResponde<Json<Vec<Alert>>> {
-        if !self.truenas_enabled {
-            return RdmResponde::ServiceDisabled;
-        }
+    pub async fn get_alerts_on_level(&self, level: Query<Option<String>>, include_dismissed: Query<Option<bool>>, instance: Query<Option<String>>) -> RdmResponde<Json<Vec<Alert>>> {
+        let settings = self.settings.load_full();
+        let names = match self.select_instances(&settings, instance.0) {
+            Ok(names) => names,
+            Err(e) => return e
+        };
 
         let level = level.0.unwrap_or("warning".to_string());
         let include_dismissed = include_dismissed.0.unwrap_or(false);
@@ -206,22 +565,22 @@ impl Api {
             _ => return RdmResponde::NotFound,
         };
 
-        let res = truenas::get_alerts(
-            &self.client,
-            self.config.truenas_address.as_ref().expect("When truenas is enabled the address has to be set"),
-            self.config.truenas_token.as_ref().expect("When truenas is enabled the address has to be set"),
-        )
-        .await;
+        let res: Vec<Vec<Alert>> = self.collector.read_alerts().await
+            .into_iter()
+            .filter(|(name, _)| names.contains(name))
+            .filter_map(|(_, alerts)| alerts)
+            .collect();
 
-        if let Some(data) = res {
-            let filtered_data: Vec<Alert> = data
-                .into_iter()
-                .filter(|item| (!item.dismissed || include_dismissed) && item.level >= minimum)
-                .collect();
-            
-            return RdmResponde::Ok(Json(filtered_data));
+        if res.is_empty() {
+            return RdmResponde::InternalServerError;
         }
 
-        RdmResponde::InternalServerError
+        let filtered_data: Vec<Alert> = res
+            .into_iter()
+            .flatten()
+            .filter(|item| (!item.dismissed || include_dismissed) && item.level >= minimum)
+            .collect();
+
+        RdmResponde::Ok(Json(filtered_data))
     }
 }
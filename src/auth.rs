@@ -0,0 +1,234 @@
+use poem::{http::{Method, StatusCode}, Endpoint, Middleware, Request, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::settings::SharedSettings;
+
+// Optional api key authentication for all api routes
+// Keys are only stored as their sha256 hash, generate a new one with --generate-key
+
+/// Header the key can be passed in, alternatively as `Authorization: Bearer [key]` or the query parameter `api_key`
+const HEADER: &str = "X-API-Key";
+const QUERY_PARAM: &str = "api_key";
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ApiKey {
+    pub name: String,
+    /// Sha256 hash of the key as hex
+    pub hash: String,
+    #[serde(default)]
+    pub scope: KeyScope
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, PartialOrd, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyScope {
+    /// Only GET requests
+    #[default]
+    Read,
+    /// Also requests that do something, like starting self-tests
+    Action
+}
+
+/// Is true if any key has the action scope, which is required to make changes on other systems (like dismissing TrueNAS alerts)
+pub fn has_action_key(keys: &[ApiKey]) -> bool {
+    keys.iter().any(|key| key.scope == KeyScope::Action)
+}
+
+pub fn validate_api_keys(keys: &[ApiKey]) -> Result<(), String> {
+    for key in keys {
+        if key.hash.len() != 64 || !key.hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!("Api key {} has an invalid hash, it has to be the sha256 hash as hex", key.name));
+        }
+    }
+
+    Ok(())
+}
+
+/// Creates a random key and returns it with its hash
+pub fn generate_key() -> (String, String) {
+    let key = format!("{}{}", uuid::Uuid::new_v4().simple(), uuid::Uuid::new_v4().simple());
+    let hash = hash(&key);
+    (key, hash)
+}
+
+fn hash(key: &str) -> String {
+    Sha256::digest(key.as_bytes()).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Rejects requests without a valid key, if any keys are configured
+///
+/// The keys are read from the current settings on every request, so they change with a config reload
+pub struct ApiKeyAuth {
+    settings: SharedSettings
+}
+
+pub fn new_auth(settings: SharedSettings) -> ApiKeyAuth {
+    ApiKeyAuth { settings }
+}
+
+impl<E: Endpoint> Middleware<E> for ApiKeyAuth {
+    type Output = ApiKeyAuthEndpoint<E>;
+
+    fn transform(&self, ep: E) -> Self::Output {
+        ApiKeyAuthEndpoint {
+            ep,
+            settings: self.settings.clone()
+        }
+    }
+}
+
+pub struct ApiKeyAuthEndpoint<E> {
+    ep: E,
+    settings: SharedSettings
+}
+
+#[poem::async_trait]
+impl<E: Endpoint> Endpoint for ApiKeyAuthEndpoint<E> {
+    type Output = E::Output;
+
+    async fn call(&self, req: Request) -> Result<Self::Output> {
+        check(&self.settings.load().config.api_keys, &req, required_scope(req.method())).map_err(poem::Error::from_status)?;
+
+        self.ep.call(req).await
+    }
+}
+
+/// Only requests that read are allowed with a read key
+fn required_scope(method: &Method) -> KeyScope {
+    if method == Method::GET || method == Method::HEAD {
+        KeyScope::Read
+    } else {
+        KeyScope::Action
+    }
+}
+
+fn check(keys: &[ApiKey], req: &Request, required: KeyScope) -> Result<(), StatusCode> {
+    if keys.is_empty() {
+        return Ok(());
+    }
+
+    let Some(key) = provided_key(req) else {
+        return Err(StatusCode::UNAUTHORIZED);
+    };
+
+    let hash = hash(&key);
+    let Some(key) = keys.iter().find(|item| item.hash.eq_ignore_ascii_case(&hash)) else {
+        return Err(StatusCode::UNAUTHORIZED);
+    };
+
+    if key.scope < required {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    Ok(())
+}
+
+fn provided_key(req: &Request) -> Option<String> {
+    if let Some(key) = req.header(HEADER) {
+        return Some(key.to_string());
+    }
+
+    if let Some(key) = req.header("Authorization").and_then(|value| value.strip_prefix("Bearer ")) {
+        return Some(key.to_string());
+    }
+
+    url::form_urlencoded::parse(req.uri().query()?.as_bytes())
+        .find(|(name, _)| name == QUERY_PARAM)
+        .map(|(_, key)| key.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const READ_KEY: &str = "readkey";
+    const ACTION_KEY: &str = "actionkey";
+
+    fn keys() -> Vec<ApiKey> {
+        vec![
+            ApiKey { name: "dashboard".to_string(), hash: hash(READ_KEY), scope: KeyScope::Read },
+            // Hashes are accepted in upper case as well
+            ApiKey { name: "automation".to_string(), hash: hash(ACTION_KEY).to_uppercase(), scope: KeyScope::Action },
+        ]
+    }
+
+    fn request(method: Method, uri: &str, header: Option<(&str, &str)>) -> Request {
+        let mut builder = Request::builder().method(method).uri(uri.parse().expect("valid uri"));
+        if let Some((name, value)) = header {
+            builder = builder.header(name, value);
+        }
+        builder.finish()
+    }
+
+    fn check_request(method: Method, uri: &str, header: Option<(&str, &str)>) -> Result<(), StatusCode> {
+        let req = request(method, uri, header);
+        check(&keys(), &req, required_scope(req.method()))
+    }
+
+    #[test]
+    fn method_to_scope() {
+        assert_eq!(required_scope(&Method::GET), KeyScope::Read);
+        assert_eq!(required_scope(&Method::HEAD), KeyScope::Read);
+        assert_eq!(required_scope(&Method::POST), KeyScope::Action);
+        assert_eq!(required_scope(&Method::PUT), KeyScope::Action);
+        assert_eq!(required_scope(&Method::DELETE), KeyScope::Action);
+        assert_eq!(required_scope(&Method::OPTIONS), KeyScope::Action);
+    }
+
+    #[test]
+    fn hash_is_sha256_hex() {
+        assert_eq!(hash("abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+
+        let (key, key_hash) = generate_key();
+        assert_eq!(hash(&key), key_hash);
+        assert!(validate_api_keys(&[ApiKey { name: "new".to_string(), hash: key_hash, scope: KeyScope::Read }]).is_ok());
+    }
+
+    #[test]
+    fn rejects_malformed_hash() {
+        let key = |hash: &str| vec![ApiKey { name: "broken".to_string(), hash: hash.to_string(), scope: KeyScope::Read }];
+
+        assert!(validate_api_keys(&key("readkey")).is_err());
+        assert!(validate_api_keys(&key(&hash(READ_KEY)[..63])).is_err());
+        assert!(validate_api_keys(&key(&format!("{}0", hash(READ_KEY)))).is_err());
+        assert!(validate_api_keys(&key(&hash(READ_KEY).replace('a', "g"))).is_err());
+        assert!(validate_api_keys(&keys()).is_ok());
+    }
+
+    #[test]
+    fn open_without_keys() {
+        let req = request(Method::POST, "/v1.0/notifications/test", None);
+        assert_eq!(check(&[], &req, KeyScope::Action), Ok(()));
+    }
+
+    #[test]
+    fn extracts_the_key() {
+        assert_eq!(check_request(Method::GET, "/v1.0/ping", Some((HEADER, READ_KEY))), Ok(()));
+        assert_eq!(check_request(Method::GET, "/v1.0/ping", Some(("x-api-key", READ_KEY))), Ok(()));
+        assert_eq!(check_request(Method::GET, "/v1.0/ping", Some(("Authorization", "Bearer readkey"))), Ok(()));
+        assert_eq!(check_request(Method::GET, "/v1.0/ping?refresh=true&api_key=readkey", None), Ok(()));
+        assert_eq!(check_request(Method::GET, "/v1.0/ping?api_key=action%6Bey", None), Ok(()));
+    }
+
+    #[test]
+    fn rejects_missing_or_wrong_key() {
+        assert_eq!(check_request(Method::GET, "/v1.0/ping", None), Err(StatusCode::UNAUTHORIZED));
+        assert_eq!(check_request(Method::GET, "/v1.0/ping", Some((HEADER, "wrongkey"))), Err(StatusCode::UNAUTHORIZED));
+        // Only the Bearer scheme is read
+        assert_eq!(check_request(Method::GET, "/v1.0/ping", Some(("Authorization", "Basic readkey"))), Err(StatusCode::UNAUTHORIZED));
+        assert_eq!(check_request(Method::GET, "/v1.0/ping?key=readkey", None), Err(StatusCode::UNAUTHORIZED));
+        // The hash itself is not a key
+        let read_hash = hash(READ_KEY);
+        assert_eq!(check_request(Method::GET, "/v1.0/ping", Some((HEADER, read_hash.as_str()))), Err(StatusCode::UNAUTHORIZED));
+    }
+
+    #[test]
+    fn read_key_can_not_post() {
+        assert_eq!(check_request(Method::POST, "/v1.0/smart/sda/selftest", Some((HEADER, READ_KEY))), Err(StatusCode::FORBIDDEN));
+        assert_eq!(check_request(Method::POST, "/v1.0/smart/sda/selftest?api_key=readkey", None), Err(StatusCode::FORBIDDEN));
+        assert_eq!(check_request(Method::POST, "/v1.0/smart/sda/selftest", Some((HEADER, ACTION_KEY))), Ok(()));
+        // Action keys can read as well
+        assert_eq!(check_request(Method::GET, "/v1.0/smart/sda/selftest", Some((HEADER, ACTION_KEY))), Ok(()));
+    }
+}
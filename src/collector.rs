@@ -0,0 +1,419 @@
+use std::{collections::HashMap, path::Path, sync::Arc, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use log::{debug, error, info, warn};
+use reqwest::Client;
+use tokio::{sync::RwLock, task::JoinHandle};
+use uuid::Uuid;
+
+use crate::{data::{Alert, AttributeHistory, Blockdevice, DrivePrediction, Prediction, Smart}, export, history::{self, History}, prediction, settings::{Settings, SharedSettings}, smart::{self, SmartReading}, truenas};
+
+// Polls the drives in the background, so the api can serve the data without shelling out on every request
+
+/// How often we check if polling got enabled by a config reload, while it is disabled
+const DISABLED_CHECK: Duration = Duration::from_secs(10);
+
+/// How often samples older than history_retention_days are deleted
+const PRUNE_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Time until reconnecting to the TrueNAS websocket, doubled after every failed attempt
+const RECONNECT_MIN: Duration = Duration::from_secs(2);
+const RECONNECT_MAX: Duration = Duration::from_secs(300);
+
+pub struct Collector {
+    settings: SharedSettings,
+    smart_enabled: bool,
+    cache: RwLock<Cache>,
+    history: Option<Arc<History>>,
+    client: Client
+}
+
+#[derive(Default)]
+struct Cache {
+    disks: Option<Vec<Blockdevice>>,
+    /// Extended smart data by drive name, each carries the time it was read
+    smart: HashMap<String, Smart>,
+    /// TrueNAS alerts by instance, only set while its websocket is connected, as they are kept up to date then
+    alerts: HashMap<String, Vec<Alert>>
+}
+
+/// The history database is only opened here, changing it requires a restart
+pub fn new_collector(settings: SharedSettings, smart_enabled: bool) -> Arc<Collector> {
+    let history = settings.load().config.history_database.as_ref().and_then(|database| {
+        // Opening reports it if the directory could not be created
+        if let Some(dir) = Path::new(database).parent() {
+            let _ = std::fs::create_dir_all(dir);
+        }
+
+        match history::open_history(Path::new(database)) {
+            Ok(history) => {
+                info!("Smart history enabled");
+                Some(Arc::new(history))
+            },
+            Err(e) => {
+                error!("Failed to open history database {}: {}", database, e);
+                None
+            }
+        }
+    });
+
+    Arc::new(Collector {
+        settings,
+        smart_enabled,
+        cache: RwLock::new(Cache::default()),
+        history,
+        client: Client::new()
+    })
+}
+
+impl Collector {
+    /// Starts the background task polling all disks every poll_interval seconds
+    ///
+    /// While poll_interval is 0 nothing is polled, then all data is read on request  
+    /// Also deletes samples older than history_retention_days from the history once a day
+    pub fn spawn(self: &Arc<Self>) {
+        if self.poll_interval() == 0 {
+            info!("Background polling disabled");
+        }
+
+        let collector = self.clone();
+        tokio::spawn(async move {
+            loop {
+                let poll_interval = collector.poll_interval();
+                if poll_interval == 0 {
+                    tokio::time::sleep(DISABLED_CHECK).await;
+                    continue;
+                }
+
+                collector.collect().await;
+
+                let alerts = collector.read_alerts().await;
+                collector.export(&alerts).await;
+                collector.notify(&alerts).await;
+
+                tokio::time::sleep(Duration::from_secs(poll_interval)).await;
+            }
+        });
+
+        if let Some(history) = self.history.clone() {
+            let settings = self.settings.clone();
+            tokio::spawn(async move {
+                loop {
+                    let retention_days = settings.load().config.history_retention_days;
+                    if retention_days != 0 {
+                        let now_ms = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_millis() as u64).unwrap_or_default();
+                        let before_ms = now_ms.saturating_sub(retention_days * 24 * 60 * 60 * 1000);
+
+                        let history = history.clone();
+                        match tokio::task::spawn_blocking(move || history.prune(before_ms)).await {
+                            Ok(Ok(deleted)) => debug!("Deleted {} samples older than {} days from the history", deleted, retention_days),
+                            Ok(Err(e)) => error!("Failed to prune the history: {}", e),
+                            Err(_) => ()
+                        }
+                    }
+
+                    tokio::time::sleep(PRUNE_INTERVAL).await;
+                }
+            });
+        }
+    }
+
+    /// Starts the background task keeping a websocket to each TrueNAS instance open, which updates the alerts as soon as they change
+    ///
+    /// A config reload reconnects all, so changes to the instances apply
+    pub fn spawn_alert_watch(self: &Arc<Self>) {
+        let collector = self.clone();
+        tokio::spawn(async move {
+            loop {
+                let settings = collector.settings.load_full();
+                let tasks: Vec<JoinHandle<()>> = if settings.config.truenas_websocket {
+                    settings.truenas_instances().iter()
+                        .map(|instance| tokio::spawn(collector.clone().watch_alerts(settings.clone(), instance.name.clone())))
+                        .collect()
+                } else {
+                    Vec::new()
+                };
+
+                while Arc::ptr_eq(&settings, &collector.settings.load_full()) {
+                    tokio::time::sleep(DISABLED_CHECK).await;
+                }
+
+                if !tasks.is_empty() {
+                    info!("Config reloaded, reconnecting to the TrueNAS websockets");
+                }
+                for task in tasks {
+                    task.abort();
+                }
+                collector.cache.write().await.alerts.clear();
+            }
+        });
+    }
+
+    async fn watch_alerts(self: Arc<Self>, settings: Arc<Settings>, name: String) {
+        let Some(instance) = settings.truenas_instance(&name) else {
+            return;
+        };
+
+        let mut reconnect = RECONNECT_MIN;
+        loop {
+            match truenas::websocket::connect(instance).await {
+                Ok(mut connection) => {
+                    info!("Connected to the websocket of TrueNAS {}, receiving alerts live", name);
+                    reconnect = RECONNECT_MIN;
+                    self.update_alerts(&name, connection.alerts()).await;
+
+                    loop {
+                        match connection.next().await {
+                            Ok(alerts) => self.update_alerts(&name, alerts).await,
+                            Err(e) => {
+                                warn!("Lost the websocket of TrueNAS {}: {}", name, e);
+                                break;
+                            }
+                        }
+                    }
+
+                    self.cache.write().await.alerts.remove(&name);
+                },
+                Err(e) => warn!("Failed to connect to the websocket of TrueNAS {}, retrying in {}s: {}", name, reconnect.as_secs(), e)
+            }
+
+            tokio::time::sleep(reconnect).await;
+            reconnect = (reconnect * 2).min(RECONNECT_MAX);
+        }
+    }
+
+    /// Stores the alerts received over the websocket and notifies about new ones right away
+    async fn update_alerts(&self, instance: &str, alerts: Vec<Alert>) {
+        debug!("Received {} alerts from TrueNAS {}", alerts.len(), instance);
+        self.cache.write().await.alerts.insert(instance.to_string(), alerts.clone());
+
+        let settings = self.settings.load_full();
+        if settings.notifier.enabled() {
+            settings.notifier.alerts(instance, &alerts, self.history.as_deref()).await;
+        }
+    }
+
+    fn poll_interval(&self) -> u64 {
+        self.settings.load().config.poll_interval
+    }
+
+    async fn collect(&self) {
+        debug!("Polling drives...");
+
+        let Some(disks) = self.read_disks().await else {
+            error!("Failed to read the disk list");
+            return;
+        };
+
+        if !self.smart_enabled {
+            return;
+        }
+
+        for disk in disks {
+            match self.read_smart(disk.name.clone()).await {
+                Some(SmartReading::Asleep(state)) => debug!("{} is in {:?}, not reading it", disk.name, state),
+                Some(SmartReading::Data(_)) => (),
+                None => debug!("Failed to read smart data of {}", disk.name)
+            }
+        }
+    }
+
+    /// Returns the alerts of each TrueNAS instance, None for instances they could not be read from
+    ///
+    /// While the websocket is connected these are the alerts received over it, otherwise they are requested now
+    pub async fn read_alerts(&self) -> Vec<(String, Option<Vec<Alert>>)> {
+        let settings = self.settings.load_full();
+
+        futures_util::future::join_all(settings.truenas_instances().iter().map(|instance| async move {
+            (instance.name.clone(), self.read_instance_alerts(instance).await)
+        })).await
+    }
+
+    /// Alerts of a single TrueNAS instance, see read_alerts
+    pub async fn read_instance_alerts(&self, instance: &truenas::Instance) -> Option<Vec<Alert>> {
+        let cached = self.cache.read().await.alerts.get(&instance.name).cloned();
+        match cached {
+            Some(alerts) => Some(alerts),
+            None => truenas::get_alerts(instance).await
+        }
+    }
+
+    /// Updates the cached alert after it was dismissed or restored, so it does not wait on the websocket
+    pub async fn set_dismissed(&self, uuid: Uuid, dismissed: bool) {
+        if let Some(alert) = self.cache.write().await.alerts.values_mut().flatten().find(|alert| alert.uuid == uuid) {
+            alert.dismissed = dismissed;
+        }
+    }
+
+    /// Pushes the cached data to InfluxDB and Graphite, if configured
+    async fn export(&self, alerts: &[(String, Option<Vec<Alert>>)]) {
+        let settings = self.settings.load_full();
+        if settings.config.influxdb.is_none() && settings.config.graphite.is_none() {
+            return;
+        }
+
+        let disks = self.cache.read().await.disks.clone().unwrap_or_default();
+        let smart = self.all_smart().await;
+        let now_ms = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_millis() as u64).unwrap_or_default();
+
+        export::push(settings.config.influxdb.as_ref(), settings.config.graphite.as_ref(), &self.client, &disks, &smart, alerts, now_ms).await;
+    }
+
+    /// Notifies about new TrueNAS alerts and drives whose health got worse since the last poll
+    async fn notify(&self, alerts: &[(String, Option<Vec<Alert>>)]) {
+        let settings = self.settings.load_full();
+        let notifier = &settings.notifier;
+        if !notifier.enabled() {
+            return;
+        }
+
+        for (instance, alerts) in alerts {
+            if let Some(alerts) = alerts {
+                notifier.alerts(instance, alerts, self.history.as_deref()).await;
+            }
+        }
+
+        let disks = self.cache.read().await.disks.clone().unwrap_or_default();
+        let smart = self.all_smart().await;
+        notifier.smart(&disks, &smart, self.history.as_deref()).await;
+
+        let now_ms = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_millis() as u64).unwrap_or_default();
+        notifier.digest(&disks, &smart, now_ms, self.history.as_deref()).await;
+    }
+
+    /// Returns the disk list, cached unless refresh is set or polling is disabled
+    pub async fn disks(&self, refresh: bool) -> Option<Vec<Blockdevice>> {
+        if !refresh && self.poll_interval() != 0 {
+            if let Some(disks) = &self.cache.read().await.disks {
+                return Some(disks.clone());
+            }
+        }
+
+        self.read_disks().await
+    }
+
+    /// Returns the extended smart data of the drive, cached unless refresh is set or polling is disabled
+    ///
+    /// If the drive is asleep the last data read is returned, and Asleep only if there is none
+    /// `drive` has to be sanitized beforehand
+    pub async fn smart(&self, drive: String, refresh: bool) -> Option<SmartReading> {
+        if !refresh && self.poll_interval() != 0 {
+            if let Some(data) = self.cache.read().await.smart.get(&drive) {
+                return Some(SmartReading::Data(Box::new(data.clone())));
+            }
+        }
+
+        self.read_smart(drive).await
+    }
+
+    /// Returns the smart data of all drives by name, from the cache or read now if polling is disabled
+    pub async fn all_smart(&self) -> Vec<(String, Smart)> {
+        if self.poll_interval() == 0 {
+            self.collect().await;
+        }
+
+        let mut list: Vec<(String, Smart)> = self.cache.read().await.smart.iter()
+            .map(|(name, data)| (name.clone(), data.clone()))
+            .collect();
+        list.sort_by(|a, b| a.0.cmp(&b.0));
+
+        list
+    }
+
+    pub fn history_enabled(&self) -> bool {
+        self.history.is_some()
+    }
+
+    /// Returns the stored history of the attribute, None if there is none or history is disabled
+    ///
+    /// `drive` has to be sanitized beforehand
+    pub async fn history(&self, drive: String, attribute: String, since: u64) -> Option<AttributeHistory> {
+        let history = self.history.clone()?;
+        let key = self.history_key(&drive).await;
+
+        tokio::task::spawn_blocking(move || history.attribute_history(&key, &attribute, since))
+            .await.ok()?
+            .map_err(|e| error!("Failed to read history of {}: {}", drive, e))
+            .ok()?
+    }
+
+    /// The key of the drive in the history, for the samples as well as the baselines
+    async fn history_key(&self, drive: &str) -> String {
+        let cache = self.cache.read().await;
+        let serial = cache.disks.as_ref()
+            .and_then(|disks| disks.iter().find(|disk| disk.name == drive))
+            .and_then(|disk| disk.serial.as_deref());
+
+        history::drive_key(serial, drive)
+    }
+
+    /// Returns the cached predictions of all drives that have one
+    pub async fn predictions(&self) -> Vec<DrivePrediction> {
+        let cache = self.cache.read().await;
+
+        let mut list: Vec<DrivePrediction> = cache.smart.iter().filter_map(|(name, data)| {
+            Some(DrivePrediction {
+                name: name.clone(),
+                serial: cache.disks.as_ref()
+                    .and_then(|disks| disks.iter().find(|disk| &disk.name == name))
+                    .and_then(|disk| disk.serial.clone()),
+                prediction: data.prediction.clone()?
+            })
+        }).collect();
+        list.sort_by(|a, b| a.name.cmp(&b.name));
+
+        list
+    }
+
+    /// Stores the sample and evaluates the trends with the updated history
+    async fn store_history(&self, drive: &str, data: Smart) -> Option<Prediction> {
+        let history = self.history.clone()?;
+        let key = self.history_key(drive).await;
+        let window_days = self.settings.load().config.prediction_window_days;
+
+        let res = tokio::task::spawn_blocking(move || {
+            history.store(&key, &data)?;
+            Ok(prediction::predict(&history, &key, &data, window_days))
+        }).await.ok()?;
+
+        res.map_err(|e: rusqlite::Error| error!("Failed to store history of {}: {}", drive, e)).ok()?
+    }
+
+    async fn read_disks(&self) -> Option<Vec<Blockdevice>> {
+        let disks = tokio::task::spawn_blocking(smart::get_disks).await.ok()??;
+
+        self.cache.write().await.disks = Some(disks.clone());
+        Some(disks)
+    }
+
+    async fn read_smart(&self, drive: String) -> Option<SmartReading> {
+        let config = self.settings.load().config.clone();
+        let name = drive.clone();
+        let key = self.history_key(&drive).await;
+        let history = self.history.clone();
+        let reading = tokio::task::spawn_blocking(move || smart::get_smart(name, true, &config, &key, history.as_deref())).await.ok()??;
+
+        let reading = match reading {
+            SmartReading::Data(mut data) => {
+                data.prediction = self.store_history(&drive, (*data).clone()).await;
+                SmartReading::Data(data)
+            },
+            asleep => asleep
+        };
+
+        let mut cache = self.cache.write().await;
+        match reading {
+            SmartReading::Data(data) => {
+                cache.smart.insert(drive, (*data).clone());
+                Some(SmartReading::Data(data))
+            },
+            SmartReading::Asleep(state) => {
+                if let Some(data) = cache.smart.get_mut(&drive) {
+                    data.power_state = Some(state);
+                    return Some(SmartReading::Data(Box::new(data.clone())));
+                }
+
+                Some(SmartReading::Asleep(state))
+            }
+        }
+    }
+}
@@ -7,12 +7,18 @@ use uuid::Uuid;
 
 #[derive(Debug, Serialize, Deserialize, Object, Clone)]
 pub struct ApiServices {
-    /// Set on bootup, is true if use_truenas is true and truenas_address and truenas_token are set
+    /// Is true if at least one TrueNAS instance is configured, changes with a config reload
     pub truenas_enabled: bool,
     /// Set on bootup, is true when user has root access (and is on Linux)
     pub smart_enabled: bool,
-    /// False if truenas_enabled is false, else this is the result of a test querry to the truenas api
-    pub truenas_status: bool
+    /// False if truenas_enabled is false, else true if all instances answered a test querry to the truenas api
+    pub truenas_status: bool,
+    /// The result of the test querry for each instance
+    pub truenas_instances: Vec<TruenasStatus>,
+    /// Set on bootup, is true if the history_database is set and could be opened
+    pub history_enabled: bool,
+    /// Set on bootup, is true if the zpool command is available
+    pub zfs_enabled: bool
 }
 
 /// A Blockdevice conntected to the machine, this can be a physical, partion, or virtual drive
@@ -48,9 +54,19 @@ pub struct Blockdevice {
     pub children: Option<Vec<Blockdevice>>
 }
 
+/// Result of the test querry to a TrueNAS instance
+#[derive(Debug, Serialize, Deserialize, Object, Clone)]
+pub struct TruenasStatus {
+    pub name: String,
+    pub status: bool
+}
+
 /// A Alert/Notification from TrueNAS
 #[derive(Debug, Serialize, Deserialize, Clone, Object)]
 pub struct Alert {
+    /// Name of the TrueNAS instance the alert is from
+    #[serde(default)]
+    pub instance: String,
     pub uuid: Uuid,
     pub source: String,
     pub klass: String,
@@ -74,16 +90,223 @@ pub enum AlertLevel {
 
 /// Smart Data from a Drive
 #[derive(Debug, Serialize, Deserialize,Clone, Object)]
+#[oai(skip_serializing_if_is_none)]
 pub struct Smart {
     pub device: SmartDevice,
+    /// Unix time in ms when this data was read from the drive
+    pub datetime_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// Set when the drive was asleep at the last read, then this is the data from the last time it was awake
+    pub power_state: Option<PowerState>,
     pub passed: bool,
-    /// This number is based on the Power_On_Hours Attribute raw value, which from personal expierence, is potentially not 1:1 hours
+    /// For Ata drives this is the decoded Power_On_Hours Attribute, as some vendors count in minutes or pack milliseconds into the raw value
     pub power_on_hours: u64,
     pub power_cycle_count: u64,
+    /// Ata Smart Attributes, this is empty for NVMe and SCSI drives
     pub attributes: Vec<SmartAttribute>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// Smart/Health Information Log, only set for NVMe drives
+    pub nvme: Option<NvmeHealth>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// Defect list and error counters, only set for SCSI/SAS drives
+    pub scsi: Option<ScsiHealth>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<SmartTemperature>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// Ata Error Log, only set when requesting extended data
+    pub error_log: Option<SmartErrorLog>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// Results of previous self-tests, most recent first, only set when requesting extended data
+    pub self_tests: Option<Vec<SelfTestEntry>>,
     /// Evaluated by this programm, as vendors are often way too lax on certain values
-    /// This is a summary of all attributes, and returns true if any are on caution
-    pub caution: bool 
+    /// This is a summary of all attributes and health data, and is the highest caution level of any of them
+    pub caution: CautionLevel,
+    /// Names of the caution rules that fired, the rules for attributes are defined in the config
+    pub caution_rules: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// Trends of the attributes indicating a failing drive, only set when the history is enabled and covers the whole prediction window
+    pub prediction: Option<Prediction>
+}
+
+/// Stored history of a Smart Attribute or health value
+#[derive(Debug, Clone, Serialize, Deserialize, Object)]
+pub struct AttributeHistory {
+    /// Either the id of the Ata attribute, or the name of the health value (like temperature, media_errors or grown_defect_list)
+    pub attribute: String,
+    /// Unix time in ms of the last sample of this drive, the last point is valid till at least then
+    pub last_sample_ms: u64,
+    /// Only changes are stored, so each point is valid until the next one, oldest first
+    pub points: Vec<HistoryPoint>
+}
+
+/// A point in the history of a Smart Attribute
+#[derive(Debug, Clone, Serialize, Deserialize, Object)]
+#[oai(skip_serializing_if_is_none)]
+pub struct HistoryPoint {
+    /// Unix time in ms when this value was first read
+    pub datetime_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// Only set for Ata attributes
+    pub value: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// Only set for Ata attributes
+    pub worst: Option<u8>,
+    pub raw: u64
+}
+
+/// Evaluation of the stored history of a drive
+#[derive(Debug, Clone, Serialize, Deserialize, Object)]
+pub struct Prediction {
+    /// Days of history the rates are calculated over
+    pub window_days: u64,
+    /// Highest caution level of all trends
+    pub caution: CautionLevel,
+    pub trends: Vec<AttributeTrend>
+}
+
+/// Trend of a single attribute or health value
+#[derive(Debug, Clone, Serialize, Deserialize, Object)]
+#[oai(skip_serializing_if_is_none)]
+pub struct AttributeTrend {
+    /// Either the id of the Ata attribute, or the name of the health value, same as for the history
+    pub attribute: String,
+    pub name: String,
+    /// Current raw value
+    pub current: u64,
+    /// Average growth of the raw value per day over the window
+    pub rate_per_day: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// Average growth of the raw value per day over the window before, if the history reaches back that far
+    pub previous_rate_per_day: Option<f64>,
+    /// True if the rate more then doubled compared to the previous window, which already had to grow
+    pub accelerating: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// Estimated days till the vendor threshold (or for NVMe the spare threshold and 100 percent used) is reached at the current rate
+    pub days_to_threshold: Option<f64>,
+    /// Warning if the value grows, Critical if it accelerates or reaches the threshold within 30 days
+    pub caution: CautionLevel
+}
+
+/// Prediction of a drive, for the overview
+#[derive(Debug, Clone, Serialize, Deserialize, Object)]
+#[oai(skip_serializing_if_is_none)]
+pub struct DrivePrediction {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub serial: Option<String>,
+    pub prediction: Prediction
+}
+
+/// Power mode of a drive that is not spun up
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Enum)]
+pub enum PowerState {
+    Idle,
+    Standby,
+    Sleep
+}
+
+/// Caution level evaluated by this programm
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Enum)]
+pub enum CautionLevel {
+    #[default]
+    Good,
+    Warning,
+    Critical
+}
+
+impl CautionLevel {
+    /// As exported to metrics, 0 good, 1 warning, 2 critical
+    pub fn as_number(&self) -> u8 {
+        match self {
+            CautionLevel::Good => 0,
+            CautionLevel::Warning => 1,
+            CautionLevel::Critical => 2
+        }
+    }
+}
+
+/// Temperature of a drive in Celsius
+#[derive(Debug, Clone, Serialize, Deserialize, Object)]
+#[oai(skip_serializing_if_is_none)]
+pub struct SmartTemperature {
+    pub current: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// Lowest temperature ever recorded, not all drives report this
+    pub lifetime_min: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// Highest temperature ever recorded, not all drives report this
+    pub lifetime_max: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// SCT Temperature History, only set when requesting extended data on drives supporting it
+    pub history: Option<TemperatureHistory>,
+    /// Evaluated by this programm, based on the configured warning and critical threshold
+    pub caution: CautionLevel
+}
+
+/// SCT Temperature History of a Ata drive
+#[derive(Debug, Clone, Serialize, Deserialize, Object)]
+pub struct TemperatureHistory {
+    /// Minutes between two readings
+    pub interval_minutes: u64,
+    /// Oldest reading first, readings the drive marked as invalid are null
+    pub readings: Vec<Option<i32>>
+}
+
+/// The Ata Error Log of a drive
+#[derive(Debug, Clone, Serialize, Deserialize, Object)]
+pub struct SmartErrorLog {
+    /// Total number of errors the drive has encountered over its lifetime
+    pub count: u64,
+    /// The most recent errors (drives usually only keep the last 5), most recent first
+    pub entries: Vec<SmartErrorEntry>
+}
+
+/// A single entry of the Ata Error Log
+#[derive(Debug, Clone, Serialize, Deserialize, Object)]
+#[oai(skip_serializing_if_is_none)]
+pub struct SmartErrorEntry {
+    pub error_number: u64,
+    /// Power on hours of the drive when the error occurred
+    pub lifetime_hours: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// Logical Block Address that was accessed when the error occurred
+    pub lba: Option<u64>
+}
+
+/// State of the Smart self-tests of a drive
+#[derive(Debug, Clone, Serialize, Deserialize, Object)]
+#[oai(skip_serializing_if_is_none)]
+pub struct SelfTestStatus {
+    /// True if a self-test is currently in progress
+    pub running: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// Progress of the running self-test in percent
+    pub progress_percent: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// Set when the drive is asleep, then the log was not read and `status` is the power state (like "standby")
+    pub power_state: Option<PowerState>,
+    /// Status as reported by the drive
+    pub status: String,
+    /// Results of previous self-tests, most recent first
+    pub results: Vec<SelfTestEntry>
+}
+
+/// A entry of the self-test log
+#[derive(Debug, Clone, Serialize, Deserialize, Object)]
+#[oai(skip_serializing_if_is_none)]
+pub struct SelfTestEntry {
+    #[serde(rename = "type")]
+    #[oai(rename = "type")]
+    pub test_type: String,
+    pub status: String,
+    pub passed: bool,
+    /// Power on hours of the drive when the test was run
+    pub lifetime_hours: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// Logical Block Address where the test encountered the first error
+    pub lba_of_first_error: Option<u64>
 }
 
 /// General Device information
@@ -98,6 +321,7 @@ pub struct SmartDevice {
 
 /// A specific Smart Attribute
 #[derive(Debug, Serialize, Deserialize,Clone, Object)]
+#[oai(skip_serializing_if_is_none)]
 pub struct SmartAttribute {
     pub id: u16,
     pub name: String,
@@ -107,12 +331,43 @@ pub struct SmartAttribute {
     pub worst: u8,
     /// Threashold for the Normalized value for this attribute to be marked as failed
     pub threshold: u8,
-    /// Vendor specific 8 byte block, but regularly is a counter
+    /// Vendor specific 6 byte block, but regularly is a counter
     pub raw: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// Set for attributes where the vendor packs multiple values into the raw value, or counts in other units
+    pub raw_decoded: Option<SmartRawDecoded>,
     pub flags: SmartFlags,
     /// Evaluated by this programm, as vendors are often way too lax on certain values
-    /// Will evaluate for certain high risk attributes the raw value, else it will flag fail if the worst drops below threashold
-    pub caution: bool,
+    /// Will evaluate the caution rules from the config, and is critical if the worst drops below threashold
+    pub caution: CautionLevel,
+}
+
+/// Decoded raw value of a Smart Attribute
+#[derive(Debug, Clone, Deserialize, Serialize, Object)]
+#[oai(skip_serializing_if_is_none)]
+pub struct SmartRawDecoded {
+    /// The actual value of the attribute, like the hours, current temperature or error count
+    pub value: u64,
+    /// Raw value as formatted by smartctl
+    pub string: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hours: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub minutes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seconds: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// Lowest temperature recorded, not all drives report this
+    pub min: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// Highest temperature recorded, not all drives report this
+    pub max: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// For Seagate error rates, the number of errors
+    pub errors: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// For Seagate error rates, the number of operations
+    pub operations: Option<u64>
 }
 
 /// Flags of a Smart Attribute
@@ -126,4 +381,218 @@ pub struct SmartFlags {
     pub error_rate: bool,
     pub event_count: bool,
     pub auto_keep: bool
-}
\ No newline at end of file
+}
+
+/// Smart/Health Information Log of a NVMe drive
+#[derive(Debug, Clone, Serialize, Deserialize, Object)]
+pub struct NvmeHealth {
+    pub critical_warning: NvmeCriticalWarning,
+    /// Composite Temperature in Celsius
+    pub temperature: i32,
+    /// Remaining spare capacity in percent
+    pub available_spare: u8,
+    /// When available_spare falls below this percentage the critical warning is raised
+    pub available_spare_threshold: u8,
+    /// Vendor specific estimate of the used up life time in percent, can exceed 100
+    pub percentage_used: u8,
+    /// Counted in units of 1000 * 512 bytes
+    pub data_units_read: u64,
+    /// Counted in units of 1000 * 512 bytes
+    pub data_units_written: u64,
+    pub power_on_hours: u64,
+    pub power_cycles: u64,
+    pub unsafe_shutdowns: u64,
+    /// Unrecovered data integrity errors, anything larger then zero is bad news
+    pub media_errors: u64,
+    pub num_err_log_entries: u64
+}
+
+/// Critical Warning bitfield of the NVMe health log
+#[derive(Debug, Clone, Serialize, Deserialize, Object)]
+pub struct NvmeCriticalWarning {
+    pub value: u8,
+    pub available_spare: bool,
+    pub temperature: bool,
+    pub reliability_degraded: bool,
+    pub read_only: bool,
+    pub volatile_memory_backup_failed: bool,
+    pub persistent_memory_read_only: bool
+}
+
+/// Health data of a SCSI/SAS drive
+#[derive(Debug, Clone, Serialize, Deserialize, Object)]
+#[oai(skip_serializing_if_is_none)]
+pub struct ScsiHealth {
+    /// Number of defects that were added to the defect list since leaving the factory
+    pub grown_defect_list: u64,
+    /// Evaluated by this programm, true if the grown defect list is larger than at the first reading ever stored for this drive  
+    /// So once it increased this stays set (together with the caution), as the baseline is never moved forward
+    pub grown_defect_list_increased: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub read_errors: Option<ScsiErrorCounter>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub write_errors: Option<ScsiErrorCounter>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub verify_errors: Option<ScsiErrorCounter>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start_stop_cycles: Option<ScsiStartStopCounter>
+}
+
+/// A single entry of the SCSI error counter log
+#[derive(Debug, Clone, Serialize, Deserialize, Object)]
+pub struct ScsiErrorCounter {
+    pub errors_corrected_by_eccfast: u64,
+    pub errors_corrected_by_eccdelayed: u64,
+    pub errors_corrected_by_rereads_rewrites: u64,
+    pub total_errors_corrected: u64,
+    pub correction_algorithm_invocations: u64,
+    /// Reported by smartctl as a string with decimal places
+    pub gigabytes_processed: String,
+    /// Anything larger then zero indicates the drive lost data
+    pub total_uncorrected_errors: u64
+}
+
+/// Start-Stop Cycle Counter of a SCSI drive
+#[derive(Debug, Clone, Serialize, Deserialize, Object)]
+#[oai(skip_serializing_if_is_none)]
+pub struct ScsiStartStopCounter {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub year_of_manufacture: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub week_of_manufacture: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub specified_cycle_count_over_device_lifetime: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub accumulated_start_stop_cycles: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub specified_load_unload_count_over_device_lifetime: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub accumulated_load_unload_cycles: Option<u64>
+}
+
+/// Result of sending a notification to one of the configured targets
+#[derive(Debug, Clone, Serialize, Deserialize, Object)]
+pub struct NotificationResult {
+    /// Type and name of the target, like "ntfy:my-topic" or "webhook:discord"
+    pub target: String,
+    pub success: bool
+}
+
+/// A ZFS pool
+#[derive(Debug, Clone, Serialize, Deserialize, Object)]
+#[oai(skip_serializing_if_is_none)]
+pub struct Pool {
+    /// Name of the TrueNAS instance, not set for pools of this machine
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub instance: Option<String>,
+    pub name: String,
+    /// ONLINE, DEGRADED, FAULTED, OFFLINE, UNAVAIL or REMOVED
+    pub state: String,
+    /// Explanation of the state, if the pool is not healthy
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+    /// Recommended action to fix the pool
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub action: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size_bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allocated_bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub free_bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fragmentation_percent: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub capacity_percent: Option<u64>,
+    /// Errors of the pool as a whole, the errors of the devices are part of the vdevs
+    /// TrueNAS does not report these, there they are the sum of the top level data vdevs
+    pub read_errors: u64,
+    pub write_errors: u64,
+    pub checksum_errors: u64,
+    /// The last or currently running scrub or resilver
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scan: Option<PoolScan>,
+    /// Top level vdevs, including logs, cache, spares and special vdevs
+    pub vdevs: Vec<Vdev>,
+    /// Data errors, like "No known data errors"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub errors: Option<String>
+}
+
+/// A vdev of a pool, either a group (like mirror or raidz) or a device
+#[derive(Debug, Clone, Serialize, Deserialize, Object)]
+#[oai(skip_serializing_if_is_none)]
+pub struct Vdev {
+    pub name: String,
+    /// mirror, raidz1, disk, file..., not known when read from the text output
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    #[oai(rename = "type")]
+    pub vdev_type: Option<String>,
+    /// normal, log, cache, spare, special or dedup
+    pub class: String,
+    pub state: String,
+    pub read_errors: u64,
+    pub write_errors: u64,
+    pub checksum_errors: u64,
+    /// Path of the device
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    /// Name of the disk the device is on, only known for TrueNAS pools
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub disk: Option<String>,
+    /// Serial number of the disk, only known for TrueNAS pools
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub serial: Option<String>,
+    /// Name of the blockdevice on this machine with the same serial, for disks passed through to TrueNAS
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host_disk: Option<String>,
+    pub children: Vec<Vdev>
+}
+
+/// A scrub or resilver
+#[derive(Debug, Clone, Serialize, Deserialize, Object)]
+#[oai(skip_serializing_if_is_none)]
+pub struct PoolScan {
+    /// scrub or resilver
+    pub function: String,
+    /// scanning, finished or canceled
+    pub state: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start_time_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub end_time_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub progress_percent: Option<f64>,
+    /// Errors the scan encountered
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub errors: Option<u64>,
+    /// The scan lines of the text output, if that was used
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>
+}
+
+/// A disk as seen by TrueNAS
+#[derive(Debug, Clone, Serialize, Deserialize, Object)]
+#[oai(skip_serializing_if_is_none)]
+pub struct TruenasDisk {
+    /// Name of the TrueNAS instance
+    pub instance: String,
+    /// Name inside of TrueNAS, like "sda"
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub serial: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size_bytes: Option<u64>,
+    /// HDD or SSD
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    #[oai(rename = "type")]
+    pub disk_type: Option<String>,
+    /// Name of the pool the disk is part of
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pool: Option<String>,
+    /// Name of the blockdevice on this machine with the same serial, for disks passed through to TrueNAS
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host_disk: Option<String>
+}
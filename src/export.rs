@@ -0,0 +1,385 @@
+use std::fmt::Write;
+
+use log::error;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use tokio::{io::AsyncWriteExt, net::{TcpStream, UdpSocket}};
+use url::Url;
+
+use crate::data::{Alert, AlertLevel, Blockdevice, Smart};
+
+// Pushes the collected data in the InfluxDB line protocol or Graphite plaintext protocol,
+// so it can end up in the same database the Proxmox metric server writes to
+
+/// Lines are sent in packets no larger then this, so they are not fragmented
+const UDP_PACKET_SIZE: usize = 1400;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct InfluxConfig {
+    /// Address of the UDP listener, like 192.168.1.10:8089
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub udp: Option<String>,
+    /// Base url of the InfluxDB v2 http api, like http://192.168.1.10:8086 (or with a path behind a reverse proxy)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub http: Option<Url>,
+    #[serde(default)]
+    pub organization: String,
+    #[serde(default)]
+    pub bucket: String,
+    #[serde(default)]
+    pub token: String
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct GraphiteConfig {
+    /// Address of the Graphite server, like 192.168.1.10:2003
+    pub address: String,
+    /// Send over UDP instead of TCP
+    #[serde(default)]
+    pub udp: bool,
+    #[serde(default = "default_graphite_prefix")]
+    pub prefix: String
+}
+
+fn default_graphite_prefix() -> String {
+    "rdm".to_string()
+}
+
+/// A measurement with tags and fields, independent of the protocol
+struct Point {
+    measurement: &'static str,
+    tags: Vec<(&'static str, String)>,
+    fields: Vec<(&'static str, Field)>,
+    timestamp_ms: u64
+}
+
+enum Field {
+    Int(i64),
+    Bool(bool)
+}
+
+/// Builds all points of the collected data, `smart` is the drive name with its data
+fn points(disks: &[Blockdevice], smart: &[(String, Smart)], alerts: &[(String, Option<Vec<Alert>>)], host: &str, now_ms: u64) -> Vec<Point> {
+    let mut list = Vec::<Point>::new();
+
+    for (name, data) in smart {
+        let disk = disks.iter().find(|disk| &disk.name == name);
+        let mut tags = vec![("host", host.to_string()), ("name", name.clone())];
+        for (key, value) in [("serial", disk.and_then(|disk| disk.serial.clone())), ("model", disk.and_then(|disk| disk.model.clone())), ("disk_id", disk.and_then(|disk| disk.disk_id.clone()))] {
+            if let Some(value) = value {
+                tags.push((key, value));
+            }
+        }
+
+        let mut fields = vec![
+            ("passed", Field::Bool(data.passed)),
+            ("caution", Field::Int(data.caution.as_number() as i64)),
+            ("power_on_hours", Field::Int(data.power_on_hours as i64)),
+            ("power_cycle_count", Field::Int(data.power_cycle_count as i64)),
+            ("asleep", Field::Bool(data.power_state.is_some())),
+        ];
+        if let Some(temp) = &data.temperature {
+            fields.push(("temperature", Field::Int(temp.current as i64)));
+        }
+        if let Some(nvme) = &data.nvme {
+            fields.push(("critical_warning", Field::Int(nvme.critical_warning.value as i64)));
+            fields.push(("available_spare", Field::Int(nvme.available_spare as i64)));
+            fields.push(("percentage_used", Field::Int(nvme.percentage_used as i64)));
+            fields.push(("data_units_read", Field::Int(nvme.data_units_read as i64)));
+            fields.push(("data_units_written", Field::Int(nvme.data_units_written as i64)));
+            fields.push(("unsafe_shutdowns", Field::Int(nvme.unsafe_shutdowns as i64)));
+            fields.push(("media_errors", Field::Int(nvme.media_errors as i64)));
+        }
+        if let Some(scsi) = &data.scsi {
+            fields.push(("grown_defect_list", Field::Int(scsi.grown_defect_list as i64)));
+        }
+        if let Some(prediction) = &data.prediction {
+            fields.push(("prediction_caution", Field::Int(prediction.caution.as_number() as i64)));
+        }
+
+        for attribute in &data.attributes {
+            let mut attribute_tags = tags.clone();
+            attribute_tags.push(("id", attribute.id.to_string()));
+            attribute_tags.push(("attribute", attribute.name.clone()));
+
+            let mut attribute_fields = vec![
+                ("value", Field::Int(attribute.value as i64)),
+                ("worst", Field::Int(attribute.worst as i64)),
+                ("threshold", Field::Int(attribute.threshold as i64)),
+                ("raw", Field::Int(i64::try_from(attribute.raw).unwrap_or(i64::MAX))),
+                ("caution", Field::Int(attribute.caution.as_number() as i64)),
+            ];
+            if let Some(decoded) = &attribute.raw_decoded {
+                attribute_fields.push(("decoded", Field::Int(i64::try_from(decoded.value).unwrap_or(i64::MAX))));
+            }
+
+            list.push(Point {
+                measurement: "smart_attribute",
+                tags: attribute_tags,
+                fields: attribute_fields,
+                timestamp_ms: data.datetime_ms
+            });
+        }
+
+        list.push(Point {
+            measurement: "smart",
+            tags,
+            fields,
+            timestamp_ms: data.datetime_ms
+        });
+    }
+
+    for (instance, alerts) in alerts {
+        let Some(alerts) = alerts else {
+            continue;
+        };
+
+        for (level, label) in [(AlertLevel::Info, "info"), (AlertLevel::Warning, "warning"), (AlertLevel::Critical, "critical"), (AlertLevel::Unknown, "unknown")] {
+            let count = alerts.iter().filter(|alert| !alert.dismissed && alert.level == level).count();
+            list.push(Point {
+                measurement: "truenas_alerts",
+                tags: vec![("host", host.to_string()), ("instance", instance.clone()), ("level", label.to_string())],
+                fields: vec![("count", Field::Int(count as i64))],
+                timestamp_ms: now_ms
+            });
+        }
+    }
+
+    list
+}
+
+/// Sends the data to all configured targets, errors are logged
+pub async fn push(influx: Option<&InfluxConfig>, graphite: Option<&GraphiteConfig>, client: &Client, disks: &[Blockdevice], smart: &[(String, Smart)], alerts: &[(String, Option<Vec<Alert>>)], now_ms: u64) {
+    let host = nix::unistd::gethostname().ok()
+        .and_then(|name| name.into_string().ok())
+        .unwrap_or("localhost".to_string());
+    let points = points(disks, smart, alerts, &host, now_ms);
+
+    if let Some(influx) = influx {
+        let lines: Vec<String> = points.iter().map(line_protocol).collect();
+
+        if let Some(address) = &influx.udp {
+            if let Err(e) = send_udp(address, &lines).await {
+                error!("Failed to send metrics to InfluxDB over UDP: {}", e);
+            }
+        }
+
+        if let Some(url) = &influx.http {
+            if let Err(e) = send_influx_http(client, url, influx, &lines).await {
+                error!("Failed to send metrics to InfluxDB over http: {}", e);
+            }
+        }
+    }
+
+    if let Some(graphite) = graphite {
+        let lines: Vec<String> = points.iter().flat_map(|point| graphite_plaintext(point, &graphite.prefix)).collect();
+
+        let res = if graphite.udp {
+            send_udp(&graphite.address, &lines).await
+        } else {
+            send_tcp(&graphite.address, &lines).await
+        };
+        if let Err(e) = res {
+            error!("Failed to send metrics to Graphite: {}", e);
+        }
+    }
+}
+
+fn line_protocol(point: &Point) -> String {
+    let escape = |value: &str| value.replace('\\', "\\\\").replace(',', "\\,").replace('=', "\\=").replace(' ', "\\ ");
+
+    let mut line = point.measurement.to_string();
+    for (key, value) in &point.tags {
+        if !value.is_empty() {
+            let _ = write!(line, ",{}={}", key, escape(value));
+        }
+    }
+
+    let fields: Vec<String> = point.fields.iter().map(|(key, value)| match value {
+        Field::Int(value) => format!("{}={}i", key, value),
+        Field::Bool(value) => format!("{}={}", key, value)
+    }).collect();
+
+    // Nanosecond precision, as this is the default of both the UDP listener and the http api
+    format!("{} {} {}", line, fields.join(","), point.timestamp_ms as u128 * 1_000_000)
+}
+
+/// Graphite has no tags, so they become part of the path, like rdm.host.sda.smart.temperature
+fn graphite_plaintext(point: &Point, prefix: &str) -> Vec<String> {
+    let escape = |value: &str| value.replace(|c: char| !c.is_ascii_alphanumeric() && c != '-' && c != '_', "_");
+
+    let mut path = vec![escape(prefix)];
+    for (key, value) in &point.tags {
+        // The serial, model and disk_id are only labels for the drive name
+        if matches!(*key, "serial" | "model" | "disk_id" | "attribute") {
+            continue;
+        }
+        path.push(escape(value));
+    }
+    path.push(point.measurement.to_string());
+    let path = path.join(".");
+
+    point.fields.iter().map(|(key, value)| {
+        let value = match value {
+            Field::Int(value) => *value,
+            Field::Bool(value) => *value as i64
+        };
+        format!("{}.{} {} {}", path, key, value, point.timestamp_ms / 1000)
+    }).collect()
+}
+
+async fn send_udp(address: &str, lines: &[String]) -> std::io::Result<()> {
+    let target = tokio::net::lookup_host(address).await?.next()
+        .ok_or(std::io::Error::new(std::io::ErrorKind::NotFound, format!("{} did not resolve to any address", address)))?;
+
+    // The socket has to be of the same address family as the target
+    let socket = UdpSocket::bind(if target.is_ipv6() { "[::]:0" } else { "0.0.0.0:0" }).await?;
+    socket.connect(target).await?;
+
+    let mut packet = String::new();
+    for line in lines {
+        if !packet.is_empty() && packet.len() + line.len() + 1 > UDP_PACKET_SIZE {
+            socket.send(packet.as_bytes()).await?;
+            packet.clear();
+        }
+        packet.push_str(line);
+        packet.push('\n');
+    }
+    if !packet.is_empty() {
+        socket.send(packet.as_bytes()).await?;
+    }
+
+    Ok(())
+}
+
+async fn send_tcp(address: &str, lines: &[String]) -> std::io::Result<()> {
+    let mut stream = TcpStream::connect(address).await?;
+    for line in lines {
+        stream.write_all(line.as_bytes()).await?;
+        stream.write_all(b"\n").await?;
+    }
+    stream.shutdown().await
+}
+
+/// Appends the write endpoint to the base url, so InfluxDB behind a reverse proxy path (like http://nas/influx/) works
+fn influx_write_url(url: &Url, config: &InfluxConfig) -> Result<Url, String> {
+    let mut target = url.clone();
+    target.path_segments_mut()
+        .map_err(|_| format!("{} can not be used as base url", url))?
+        .pop_if_empty()
+        .extend(["api", "v2", "write"]);
+    target.query_pairs_mut()
+        .append_pair("org", &config.organization)
+        .append_pair("bucket", &config.bucket)
+        .append_pair("precision", "ns");
+
+    Ok(target)
+}
+
+async fn send_influx_http(client: &Client, url: &Url, config: &InfluxConfig, lines: &[String]) -> Result<(), String> {
+    let target = influx_write_url(url, config)?;
+
+    let res = client.post(target)
+        .header("Authorization", format!("Token {}", config.token))
+        .body(lines.join("\n"))
+        .send().await
+        .map_err(|e| e.to_string())?;
+
+    if !res.status().is_success() {
+        return Err(format!("{} {}", res.status(), res.text().await.unwrap_or_default()));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn disk() -> Blockdevice {
+        serde_json::from_str(r#"{
+            "name": "sda", "removable": false, "size_kb": 3907018584, "read_only": false, "type": "disk", "maj:min": "8:0",
+            "model": "WDC WD40EFRX-68N32N0", "serial": "WD-WCC7K1234567", "disk_id": "ata-WDC_WD40EFRX-68N32N0_WD-WCC7K1234567"
+        }"#).expect("disk parses")
+    }
+
+    fn smart() -> Smart {
+        serde_json::from_str(r#"{
+            "device": { "name": "/dev/sda", "type": "sat", "protocol": "ATA" },
+            "datetime_ms": 1700000000500,
+            "passed": true,
+            "power_on_hours": 12000,
+            "power_cycle_count": 42,
+            "attributes": [ {
+                "id": 199, "name": "UDMA_CRC_Error_Count", "value": 200, "worst": 200, "threshold": 0, "raw": 3,
+                "flags": { "value": 50, "string": "-O--CK ", "prefailure": false, "updated_online": true, "performance": false, "error_rate": false, "event_count": true, "auto_keep": true },
+                "caution": "Warning"
+            } ],
+            "temperature": { "current": 34, "caution": "Good" },
+            "caution": "Warning",
+            "caution_rules": [ "CRC Errors" ]
+        }"#).expect("smart parses")
+    }
+
+    fn influx() -> InfluxConfig {
+        InfluxConfig {
+            udp: None,
+            http: None,
+            organization: "my org".to_string(),
+            bucket: "proxmox".to_string(),
+            token: "token".to_string()
+        }
+    }
+
+    #[test]
+    fn line_protocol_escapes_tags() {
+        let point = Point {
+            measurement: "smart",
+            tags: vec![("host", "pve".to_string()), ("model", "WDC WD40EFRX, rev=2".to_string()), ("serial", String::new()), ("disk_id", "a\\b".to_string())],
+            fields: vec![("passed", Field::Bool(true)), ("caution", Field::Int(1))],
+            timestamp_ms: 1700000000500
+        };
+
+        // Empty tags are left out, as InfluxDB rejects them
+        assert_eq!(line_protocol(&point), r"smart,host=pve,model=WDC\ WD40EFRX\,\ rev\=2,disk_id=a\\b passed=true,caution=1i 1700000000500000000");
+    }
+
+    #[test]
+    fn points_to_line_protocol() {
+        let points = points(&[disk()], &[("sda".to_string(), smart())], &[("vm 1".to_string(), Some(Vec::new())), ("down".to_string(), None)], "pve", 1700000001000);
+        let lines: Vec<String> = points.iter().map(line_protocol).collect();
+
+        assert_eq!(lines, vec![
+            r"smart_attribute,host=pve,name=sda,serial=WD-WCC7K1234567,model=WDC\ WD40EFRX-68N32N0,disk_id=ata-WDC_WD40EFRX-68N32N0_WD-WCC7K1234567,id=199,attribute=UDMA_CRC_Error_Count value=200i,worst=200i,threshold=0i,raw=3i,caution=1i 1700000000500000000",
+            r"smart,host=pve,name=sda,serial=WD-WCC7K1234567,model=WDC\ WD40EFRX-68N32N0,disk_id=ata-WDC_WD40EFRX-68N32N0_WD-WCC7K1234567 passed=true,caution=1i,power_on_hours=12000i,power_cycle_count=42i,asleep=false,temperature=34i 1700000000500000000",
+            r"truenas_alerts,host=pve,instance=vm\ 1,level=info count=0i 1700000001000000000",
+            r"truenas_alerts,host=pve,instance=vm\ 1,level=warning count=0i 1700000001000000000",
+            r"truenas_alerts,host=pve,instance=vm\ 1,level=critical count=0i 1700000001000000000",
+            r"truenas_alerts,host=pve,instance=vm\ 1,level=unknown count=0i 1700000001000000000",
+        ]);
+    }
+
+    #[test]
+    fn graphite_paths() {
+        let points = points(&[disk()], &[("sda".to_string(), smart())], &[("vm 1".to_string(), Some(Vec::new()))], "pve.lan", 1700000001000);
+        let lines: Vec<String> = points.iter().flat_map(|point| graphite_plaintext(point, "rdm")).collect();
+
+        // Serial, model and attribute name are left out of the path, dots and spaces are replaced
+        assert!(lines.contains(&"rdm.pve_lan.sda.199.smart_attribute.raw 3 1700000000".to_string()));
+        assert!(lines.contains(&"rdm.pve_lan.sda.smart.temperature 34 1700000000".to_string()));
+        assert!(lines.contains(&"rdm.pve_lan.sda.smart.passed 1 1700000000".to_string()));
+        assert!(lines.contains(&"rdm.pve_lan.sda.smart.asleep 0 1700000000".to_string()));
+        assert!(lines.contains(&"rdm.pve_lan.vm_1.info.truenas_alerts.count 0 1700000001".to_string()));
+        assert!(lines.iter().all(|line| line.split(' ').count() == 3));
+    }
+
+    #[test]
+    fn write_url_keeps_the_base_path() {
+        let url = |base: &str| influx_write_url(&Url::parse(base).expect("valid url"), &influx()).expect("valid base").to_string();
+
+        assert_eq!(url("http://192.168.1.10:8086"), "http://192.168.1.10:8086/api/v2/write?org=my+org&bucket=proxmox&precision=ns");
+        assert_eq!(url("http://192.168.1.10:8086/"), "http://192.168.1.10:8086/api/v2/write?org=my+org&bucket=proxmox&precision=ns");
+        assert_eq!(url("https://nas.lan/influx/"), "https://nas.lan/influx/api/v2/write?org=my+org&bucket=proxmox&precision=ns");
+        assert_eq!(url("https://nas.lan/influx"), "https://nas.lan/influx/api/v2/write?org=my+org&bucket=proxmox&precision=ns");
+    }
+}
@@ -0,0 +1,263 @@
+use std::{path::Path, sync::Mutex};
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::data::{AttributeHistory, HistoryPoint, Smart};
+
+// Stores the smart samples the collector reads in a sqlite database
+// Only changes are written, so each row is valid until the next row of the same drive and attribute
+
+pub struct History {
+    connection: Mutex<Connection>
+}
+
+/// A single value of a sample, attribute is either the Ata attribute id or the name of a health value (like media_errors)
+struct Sample {
+    attribute: String,
+    value: Option<u8>,
+    worst: Option<u8>,
+    raw: i64
+}
+
+/// Values of an attribute when the drive was first read, changes (like grown defects or delta rules) are relative to it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Baseline {
+    pub value: Option<u8>,
+    pub worst: Option<u8>,
+    pub raw: u64,
+    pub decoded: u64
+}
+
+/// Key the samples and baselines of a drive are stored under, the serial number as device names can change between boots
+///
+/// Only drives without a serial fall back to the device name (like sda)
+pub fn drive_key(serial: Option<&str>, drive: &str) -> String {
+    serial.map(str::trim)
+        .filter(|serial| !serial.is_empty())
+        .unwrap_or(drive)
+        .to_string()
+}
+
+pub fn open_history(path: &Path) -> rusqlite::Result<History> {
+    let connection = Connection::open(path)?;
+    connection.execute_batch("
+        CREATE TABLE IF NOT EXISTS samples (
+            drive TEXT NOT NULL,
+            attribute TEXT NOT NULL,
+            datetime_ms INTEGER NOT NULL,
+            value INTEGER,
+            worst INTEGER,
+            raw INTEGER NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS samples_by_attribute ON samples (drive, attribute, datetime_ms);
+        CREATE TABLE IF NOT EXISTS drives (
+            drive TEXT PRIMARY KEY,
+            last_sample_ms INTEGER NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS baselines (
+            drive TEXT NOT NULL,
+            attribute TEXT NOT NULL,
+            value INTEGER,
+            worst INTEGER,
+            raw INTEGER NOT NULL,
+            decoded INTEGER NOT NULL,
+            PRIMARY KEY (drive, attribute)
+        );
+        CREATE TABLE IF NOT EXISTS state (
+            name TEXT PRIMARY KEY,
+            value TEXT NOT NULL
+        );
+    ")?;
+
+    Ok(History {
+        connection: Mutex::new(connection)
+    })
+}
+
+impl History {
+    /// Writes all values of the sample that changed since the last one
+    ///
+    /// `drive` should be the serial number, as device names can change between boots
+    pub fn store(&self, drive: &str, smart: &Smart) -> rusqlite::Result<()> {
+        let mut connection = self.connection.lock().expect("history connection lock poisoned");
+        let transaction = connection.transaction()?;
+
+        {
+            let mut last = transaction.prepare_cached("
+                SELECT value, worst, raw FROM samples
+                WHERE drive = ?1 AND attribute = ?2
+                ORDER BY datetime_ms DESC LIMIT 1
+            ")?;
+            let mut insert = transaction.prepare_cached("
+                INSERT INTO samples (drive, attribute, datetime_ms, value, worst, raw) VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+            ")?;
+
+            for sample in samples(smart) {
+                let previous: Option<(Option<u8>, Option<u8>, i64)> = last.query_row(params![drive, sample.attribute], |row| {
+                    Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+                }).optional()?;
+
+                if previous != Some((sample.value, sample.worst, sample.raw)) {
+                    insert.execute(params![drive, sample.attribute, smart.datetime_ms as i64, sample.value, sample.worst, sample.raw])?;
+                }
+            }
+
+            transaction.execute("
+                INSERT INTO drives (drive, last_sample_ms) VALUES (?1, ?2)
+                ON CONFLICT (drive) DO UPDATE SET last_sample_ms = excluded.last_sample_ms
+            ", params![drive, smart.datetime_ms as i64])?;
+        }
+
+        transaction.commit()
+    }
+
+    /// Deletes the samples before the cutoff, except the last one of each drive and attribute, as it is still valid at the cutoff
+    ///
+    /// Returns the number of deleted samples
+    pub fn prune(&self, before_ms: u64) -> rusqlite::Result<usize> {
+        let connection = self.connection.lock().expect("history connection lock poisoned");
+
+        connection.execute("
+            DELETE FROM samples WHERE rowid IN (
+                SELECT rowid FROM (
+                    SELECT rowid, ROW_NUMBER() OVER (PARTITION BY drive, attribute ORDER BY datetime_ms DESC) AS newer
+                    FROM samples WHERE datetime_ms < ?1
+                ) WHERE newer > 1
+            )
+        ", params![before_ms as i64])
+    }
+
+    /// Returns the stored baseline of the attribute, `current` becomes the baseline if there is none yet
+    ///
+    /// `drive` is the serial number (or device name), `attribute` like in the samples
+    pub fn baseline(&self, drive: &str, attribute: &str, current: Baseline) -> rusqlite::Result<Baseline> {
+        let connection = self.connection.lock().expect("history connection lock poisoned");
+
+        connection.execute("
+            INSERT INTO baselines (drive, attribute, value, worst, raw, decoded) VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+            ON CONFLICT (drive, attribute) DO NOTHING
+        ", params![drive, attribute, current.value, current.worst, i64::try_from(current.raw).unwrap_or(i64::MAX), i64::try_from(current.decoded).unwrap_or(i64::MAX)])?;
+
+        connection.query_row("
+            SELECT value, worst, raw, decoded FROM baselines WHERE drive = ?1 AND attribute = ?2
+        ", params![drive, attribute], |row| {
+            Ok(Baseline {
+                value: row.get(0)?,
+                worst: row.get(1)?,
+                raw: row.get::<_, i64>(2)? as u64,
+                decoded: row.get::<_, i64>(3)? as u64
+            })
+        })
+    }
+
+    /// Returns the state stored under the name by another part of the programm (like the notifier), as json
+    pub fn state(&self, name: &str) -> rusqlite::Result<Option<String>> {
+        let connection = self.connection.lock().expect("history connection lock poisoned");
+
+        connection.query_row("SELECT value FROM state WHERE name = ?1", params![name], |row| row.get(0)).optional()
+    }
+
+    /// Stores the state under the name, replacing the previous one
+    pub fn set_state(&self, name: &str, value: &str) -> rusqlite::Result<()> {
+        let connection = self.connection.lock().expect("history connection lock poisoned");
+
+        connection.execute("
+            INSERT INTO state (name, value) VALUES (?1, ?2)
+            ON CONFLICT (name) DO UPDATE SET value = excluded.value
+        ", params![name, value])?;
+        Ok(())
+    }
+
+    /// Returns all changes of the attribute after since, and the value it had at since
+    ///
+    /// None if there are no samples for this drive and attribute
+    pub fn attribute_history(&self, drive: &str, attribute: &str, since: u64) -> rusqlite::Result<Option<AttributeHistory>> {
+        let connection = self.connection.lock().expect("history connection lock poisoned");
+
+        let last_sample_ms: Option<i64> = connection.query_row(
+            "SELECT last_sample_ms FROM drives WHERE drive = ?1", params![drive], |row| row.get(0)
+        ).optional()?;
+        let Some(last_sample_ms) = last_sample_ms else {
+            return Ok(None);
+        };
+
+        let mut statement = connection.prepare("
+            SELECT datetime_ms, value, worst, raw FROM samples
+            WHERE drive = ?1 AND attribute = ?2 AND datetime_ms >= (
+                SELECT COALESCE(MAX(datetime_ms), 0) FROM samples
+                WHERE drive = ?1 AND attribute = ?2 AND datetime_ms <= ?3
+            )
+            ORDER BY datetime_ms ASC
+        ")?;
+        let points = statement.query_map(params![drive, attribute, since as i64], |row| {
+            Ok(HistoryPoint {
+                datetime_ms: row.get::<_, i64>(0)? as u64,
+                value: row.get(1)?,
+                worst: row.get(2)?,
+                raw: row.get::<_, i64>(3)? as u64
+            })
+        })?.collect::<rusqlite::Result<Vec<HistoryPoint>>>()?;
+
+        if points.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(AttributeHistory {
+            attribute: attribute.to_string(),
+            last_sample_ms: last_sample_ms as u64,
+            points
+        }))
+    }
+}
+
+fn samples(smart: &Smart) -> Vec<Sample> {
+    fn health(attribute: &str, raw: u64) -> Sample {
+        Sample {
+            attribute: attribute.to_string(),
+            value: None,
+            worst: None,
+            raw: i64::try_from(raw).unwrap_or(i64::MAX)
+        }
+    }
+
+    let mut list: Vec<Sample> = smart.attributes.iter().map(|item| {
+        Sample {
+            attribute: item.id.to_string(),
+            value: Some(item.value),
+            worst: Some(item.worst),
+            raw: i64::try_from(item.raw).unwrap_or(i64::MAX)
+        }
+    }).collect();
+
+    if let Some(temp) = &smart.temperature {
+        list.push(Sample {
+            attribute: "temperature".to_string(),
+            value: None,
+            worst: None,
+            raw: temp.current as i64
+        });
+    }
+
+    if let Some(nvme) = &smart.nvme {
+        list.push(health("critical_warning", nvme.critical_warning.value as u64));
+        list.push(health("available_spare", nvme.available_spare as u64));
+        list.push(health("percentage_used", nvme.percentage_used as u64));
+        list.push(health("data_units_read", nvme.data_units_read));
+        list.push(health("data_units_written", nvme.data_units_written));
+        list.push(health("unsafe_shutdowns", nvme.unsafe_shutdowns));
+        list.push(health("media_errors", nvme.media_errors));
+        list.push(health("num_err_log_entries", nvme.num_err_log_entries));
+    }
+
+    if let Some(scsi) = &smart.scsi {
+        list.push(health("grown_defect_list", scsi.grown_defect_list));
+        if let Some(read) = &scsi.read_errors {
+            list.push(health("read_uncorrected_errors", read.total_uncorrected_errors));
+        }
+        if let Some(write) = &scsi.write_errors {
+            list.push(health("write_uncorrected_errors", write.total_uncorrected_errors));
+        }
+    }
+
+    list
+}
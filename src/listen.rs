@@ -0,0 +1,219 @@
+use std::{fs, io, net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6}};
+
+use log::info;
+use poem::listener::{AcceptorExt, BoxAcceptor, Listener, TcpListener, UnixAcceptor};
+
+use crate::{tls, Config};
+
+// Binds all addresses the api is served on, tcp addresses (with tls if enabled) and unix sockets
+
+const UNIX_PREFIX: &str = "unix:";
+
+/// Binds the `listen` addresses of the config, or 0.0.0.0 with the port if there are none
+pub async fn bind(config: &Config) -> io::Result<BoxAcceptor> {
+    let addresses = if config.listen.is_empty() {
+        vec![format!("0.0.0.0:{}", config.port)]
+    } else {
+        config.listen.clone()
+    };
+
+    let mut tcp: Option<BoxAcceptor> = None;
+    let mut unix: Vec<BoxAcceptor> = Vec::new();
+
+    for address in addresses {
+        let (acceptor, address) = match parse_address(&address, config.port)? {
+            Address::Unix(path) => {
+                unix.push(bind_unix(&path, &config.unix_socket_mode)?.boxed());
+                info!("Listening on unix socket {}", path);
+                continue;
+            },
+            Address::Ip(ip) => (TcpListener::bind(ip).into_acceptor().await.map(|acceptor| acceptor.boxed()), ip.to_string()),
+            Address::Host(host) => (TcpListener::bind(host.clone()).into_acceptor().await.map(|acceptor| acceptor.boxed()), host)
+        };
+        let acceptor = acceptor.map_err(|e| io::Error::new(e.kind(), format!("Failed to bind {}: {}", address, e)))?;
+        info!("Listening on {}", address);
+
+        tcp = Some(match tcp {
+            Some(previous) => previous.combine(acceptor).boxed(),
+            None => acceptor
+        });
+    }
+
+    // Tls only applies to tcp, unix sockets are expected to be behind a local reverse proxy
+    let mut acceptor = match (tcp, &config.tls) {
+        (Some(tcp), Some(tls)) => {
+            tls::prepare(tls)?;
+            info!("TLS enabled{}", if tls.client_ca.is_some() { ", client certificates are required" } else { "" });
+            Some(tcp.rustls(Box::pin(tls::watch(tls.clone()))).boxed())
+        },
+        (tcp, _) => tcp
+    };
+
+    for socket in unix {
+        acceptor = Some(match acceptor {
+            Some(previous) => previous.combine(socket).boxed(),
+            None => socket
+        });
+    }
+
+    acceptor.ok_or(io::Error::new(io::ErrorKind::InvalidInput, "No listen address set"))
+}
+
+#[derive(Debug, PartialEq)]
+enum Address {
+    /// Path of the socket
+    Unix(String),
+    Ip(SocketAddr),
+    /// Host name with port, resolved when binding
+    Host(String)
+}
+
+/// Parses an address of `listen`, addresses without a port (like [::] or nas.lan) get the port from the config
+///
+/// Link local IPv6 addresses can have a scope, either the interface name or index (like fe80::1%eth0)
+fn parse_address(address: &str, port: u16) -> io::Result<Address> {
+    if let Some(path) = address.strip_prefix(UNIX_PREFIX) {
+        return Ok(Address::Unix(path.to_string()));
+    }
+
+    if let Ok(ip) = address.parse::<SocketAddr>() {
+        return Ok(Address::Ip(ip));
+    }
+
+    // Either [ip] or [ip]:port, the brackets are only required with a port
+    let (ip, ip_port) = match address.strip_prefix('[').and_then(|rest| rest.split_once(']')) {
+        Some((ip, "")) => (ip, port),
+        Some((ip, rest)) => match rest.strip_prefix(':').and_then(|rest| rest.parse::<u16>().ok()) {
+            Some(port) => (ip, port),
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid listen address {}", address)))
+        },
+        None => (address, port)
+    };
+
+    match ip.split_once('%') {
+        Some((ip, scope)) => {
+            let ip = ip.parse::<Ipv6Addr>()
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid listen address {}, only IPv6 addresses can have a scope", address)))?;
+            let scope = match scope.parse::<u32>() {
+                Ok(index) => index,
+                Err(_) => nix::net::if_::if_nametoindex(scope)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("Unknown interface {} in listen address {}: {}", scope, address, e)))?
+            };
+            Ok(Address::Ip(SocketAddr::V6(SocketAddrV6::new(ip, ip_port, 0, scope))))
+        },
+        None => match ip.parse::<IpAddr>() {
+            Ok(ip) => Ok(Address::Ip(SocketAddr::new(ip, ip_port))),
+            Err(_) if !has_port(address) => Ok(Address::Host(format!("{}:{}", address, port))),
+            Err(_) => Ok(Address::Host(address.to_string()))
+        }
+    }
+}
+
+/// Ip addresses with a port, or host names followed by one (host names can not contain colons)
+fn has_port(address: &str) -> bool {
+    address.rsplit_once(':')
+        .map(|(_, port)| port.parse::<u16>().is_ok())
+        .unwrap_or(false)
+}
+
+fn bind_unix(path: &str, mode: &str) -> io::Result<UnixAcceptor> {
+    use std::os::unix::fs::FileTypeExt;
+
+    let mode = u32::from_str_radix(mode, 8)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid unix_socket_mode {}, has to be octal like 660", mode)))?;
+
+    // A socket left over from the last run would block binding
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            fs::remove_file(path)?;
+        }
+    }
+
+    // The socket is created with the permissions left by the umask, so it is never more open than the mode,
+    // setting them after binding would leave a moment where anyone could connect
+    let umask = nix::sys::stat::umask(nix::sys::stat::Mode::from_bits_truncate(!mode & 0o777));
+    let listener = std::os::unix::net::UnixListener::bind(path);
+    nix::sys::stat::umask(umask);
+
+    let listener = listener.map_err(|e| io::Error::new(e.kind(), format!("Failed to bind {}: {}", path, e)))?;
+    listener.set_nonblocking(true)?;
+
+    UnixAcceptor::from_std(listener)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    fn ip(address: &str) -> SocketAddr {
+        address.parse().expect("valid socket address")
+    }
+
+    #[test]
+    fn parses_ip_addresses() {
+        assert_eq!(parse_address("192.168.1.10", 30603).ok(), Some(Address::Ip(ip("192.168.1.10:30603"))));
+        assert_eq!(parse_address("192.168.1.10:8080", 30603).ok(), Some(Address::Ip(ip("192.168.1.10:8080"))));
+        assert_eq!(parse_address("::", 30603).ok(), Some(Address::Ip(ip("[::]:30603"))));
+        assert_eq!(parse_address("[::]", 30603).ok(), Some(Address::Ip(ip("[::]:30603"))));
+        assert_eq!(parse_address("[2001:db8::5]:8080", 30603).ok(), Some(Address::Ip(ip("[2001:db8::5]:8080"))));
+        assert_eq!(parse_address("0.0.0.0", 443).ok(), Some(Address::Ip(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 443))));
+    }
+
+    #[test]
+    fn parses_scoped_ipv6() {
+        let scoped = |port: u16, scope: u32| Address::Ip(SocketAddr::V6(SocketAddrV6::new("fe80::1".parse().expect("valid ip"), port, 0, scope)));
+        let loopback = nix::net::if_::if_nametoindex("lo").expect("loopback interface exists");
+
+        assert_eq!(parse_address("fe80::1%lo", 30603).ok(), Some(scoped(30603, loopback)));
+        assert_eq!(parse_address("[fe80::1%lo]", 30603).ok(), Some(scoped(30603, loopback)));
+        assert_eq!(parse_address("[fe80::1%lo]:8080", 30603).ok(), Some(scoped(8080, loopback)));
+        assert_eq!(parse_address("fe80::1%3", 30603).ok(), Some(scoped(30603, 3)));
+        assert_eq!(parse_address("[fe80::1%3]:8080", 30603).ok(), Some(scoped(8080, 3)));
+
+        assert!(parse_address("fe80::1%no-such-interface", 30603).is_err());
+        assert!(parse_address("192.168.1.10%lo", 30603).is_err());
+    }
+
+    #[test]
+    fn parses_host_names() {
+        assert_eq!(parse_address("nas.lan", 30603).ok(), Some(Address::Host("nas.lan:30603".to_string())));
+        assert_eq!(parse_address("nas.lan:8080", 30603).ok(), Some(Address::Host("nas.lan:8080".to_string())));
+        assert_eq!(parse_address("localhost", 30603).ok(), Some(Address::Host("localhost:30603".to_string())));
+    }
+
+    #[test]
+    fn parses_unix_sockets() {
+        assert_eq!(parse_address("unix:/run/rdm.sock", 30603).ok(), Some(Address::Unix("/run/rdm.sock".to_string())));
+        assert_eq!(parse_address("unix:rdm.sock", 30603).ok(), Some(Address::Unix("rdm.sock".to_string())));
+    }
+
+    #[test]
+    fn rejects_invalid_ports() {
+        assert!(parse_address("[::1]:http", 30603).is_err());
+        assert!(parse_address("[::1]8080", 30603).is_err());
+    }
+
+    #[tokio::test]
+    async fn unix_socket_gets_the_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("rdm-listen-{}", uuid::Uuid::new_v4().simple()));
+        fs::create_dir_all(&dir).expect("temp dir is created");
+        let path = dir.join("rdm.sock");
+        let path = path.to_str().expect("temp path is utf-8");
+
+        let acceptor = bind_unix(path, "660").expect("socket binds");
+        let mode = fs::metadata(path).expect("socket exists").permissions().mode();
+        assert_eq!(mode & 0o777, 0o660);
+
+        // A socket left over from the last run is replaced
+        drop(acceptor);
+        let _acceptor = bind_unix(path, "600").expect("socket binds again");
+        assert_eq!(fs::metadata(path).expect("socket exists").permissions().mode() & 0o777, 0o600);
+
+        assert!(bind_unix(path, "rw").is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
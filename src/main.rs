@@ -1,19 +1,32 @@
 mod api;
+mod auth;
+mod collector;
+mod export;
+mod history;
+mod metrics;
+mod notify;
+mod prediction;
+mod settings;
 pub mod smart;
 pub mod truenas;
 pub mod data;
+mod tls;
 mod installer;
+mod listen;
+mod zfs;
 
 mod built_info {
     include!(concat!(env!("OUT_DIR"), "/built.rs"));
 }
 
-use std::fs;
+use std::{fs, sync::Arc};
+
+use arc_swap::ArcSwap;
 
 //use actix_web::{HttpServer, App, middleware::Logger, web::Data};
 use clap::Parser;
 use log::{error, info};
-use poem::{Route, listener::TcpListener};
+use poem::{EndpointExt, Route};
 use poem_openapi::OpenApiService;
 use serde::{Deserialize, Serialize};
 
@@ -36,19 +49,41 @@ async fn main() -> std::io::Result<()> {
 
         return Ok(()); 
     }
+
+    if args.generate_key {
+        let (key, hash) = auth::generate_key();
+        println!("Key: {}", key);
+        println!("Add this to api_keys in the config (scope read or action):");
+        println!("{}", serde_json::to_string_pretty(&auth::ApiKey { name: "new key".to_string(), hash, scope: auth::KeyScope::Read }).unwrap_or_default());
+        return Ok(());
+    }
     
-    if let Some(config) = get_config() {
-        let port = config.port;
-        info!("Launching Server on port {}", port);
+    if let Some(config) = get_config(true) {
+        info!("Launching Server...");
+        let acceptor = match listen::bind(&config).await {
+            Ok(acceptor) => acceptor,
+            Err(e) => {
+                error!("{}, aborting launch", e);
+                return Err(e);
+            }
+        };
+
+        if config.api_keys.is_empty() {
+            info!("No api keys set, the api is open to everyone on the network");
+        }
 
-        let api_service = OpenApiService::new(api::new_api(config), "Restless Drive Monitor", built_info::PKG_VERSION).server("/v1.0");
+        let settings = Arc::new(ArcSwap::from_pointee(settings::new_settings(config, None)));
+        settings::spawn_reload(settings.clone());
+
+        let auth = auth::new_auth(settings.clone());
+        let api_service = OpenApiService::new(api::new_api(settings), "Restless Drive Monitor", built_info::PKG_VERSION).server("/v1.0");
         
         let doc = api_service.swagger_ui();
         let app = Route::new()
-                .nest("/v1.0", api_service)
+                .nest("/v1.0", api_service.with(auth))
                 .nest("/doc", doc);
 
-        poem::Server::new(TcpListener::bind(format!("0.0.0.0:{}", port)))
+        poem::Server::new_with_acceptor(acceptor)
             .run(app)
             .await
     } else {
@@ -63,17 +98,94 @@ pub struct Config {
     pub truenas_address: Option<url::Url>,
     pub truenas_token: Option<String>,
     pub accept_invalid_certs: bool,
-    pub port: u16
+    /// Further TrueNAS servers, each with a name, address, token and accept_invalid_certs
+    #[serde(default)]
+    pub truenas_instances: Vec<truenas::TruenasConfig>,
+    /// Receives the TrueNAS alerts over the websocket api as soon as they change, instead of only on every poll
+    #[serde(default = "default_truenas_websocket")]
+    pub truenas_websocket: bool,
+    pub port: u16,
+    #[serde(default)]
+    pub temperature_warning: Option<i32>,
+    #[serde(default)]
+    pub temperature_critical: Option<i32>,
+    #[serde(default = "smart::default_caution_rules")]
+    pub caution_rules: Vec<smart::CautionRule>,
+    /// Seconds between polling the drives in the background, 0 disables it
+    #[serde(default = "default_poll_interval")]
+    pub poll_interval: u64,
+    /// Drives in this power mode (or lower) are not read, as this would spin them up
+    #[serde(default)]
+    pub standby_check: smart::StandbyCheck,
+    /// Path of the sqlite database the smart history is stored in, relative to the config file, null disables it
+    #[serde(default = "default_history_database")]
+    pub history_database: Option<String>,
+    /// Days samples are kept in the history (the value at the cutoff stays), 0 keeps them forever
+    #[serde(default = "default_history_retention")]
+    pub history_retention_days: u64,
+    /// Days of history the trends of attributes are calculated over
+    #[serde(default = "default_prediction_window")]
+    pub prediction_window_days: u64,
+    /// Pushes the data to InfluxDB after every poll, null disables it
+    #[serde(default)]
+    pub influxdb: Option<export::InfluxConfig>,
+    /// Pushes the data to Graphite after every poll, null disables it
+    #[serde(default)]
+    pub graphite: Option<export::GraphiteConfig>,
+    /// Targets new TrueNAS alerts and drives getting worse are sent to
+    #[serde(default)]
+    pub notifications: notify::NotifyConfig,
+    /// If any are set, every request to the api needs one of these keys
+    #[serde(default)]
+    pub api_keys: Vec<auth::ApiKey>,
+    /// Serves the api over https, null for plain http
+    #[serde(default)]
+    pub tls: Option<tls::TlsConfig>,
+    /// Addresses the api is served on, like "192.168.1.10", "[::]:8080" or "unix:/run/rdm.sock", if empty 0.0.0.0 is used
+    /// Addresses without a port use the port above
+    #[serde(default)]
+    pub listen: Vec<String>,
+    /// Permissions of unix sockets in octal
+    #[serde(default = "default_unix_socket_mode")]
+    pub unix_socket_mode: String
+}
+
+fn default_truenas_websocket() -> bool {
+    true
+}
+
+fn default_unix_socket_mode() -> String {
+    "660".to_string()
 }
 
-pub fn get_config() -> Option<Config> {
+fn default_prediction_window() -> u64 {
+    30
+}
+
+fn default_history_database() -> Option<String> {
+    Some("/var/lib/restless_drive_monitor/rdm_history.db".to_string())
+}
+
+fn default_history_retention() -> u64 {
+    365
+}
+
+fn default_poll_interval() -> u64 {
+    300
+}
+
+/// Reads the config, with create_missing a default config is written if there is none
+pub fn get_config(create_missing: bool) -> Option<Config> {
     let args = Args::parse();
 
     let path = std::path::PathBuf::from(match args.config {
         Some(p) => p,
         None => "./rdm.conf".to_string()
     });
-    if !path.exists() {
+    if !path.exists() && !create_missing {
+        error!("Config file {} does not exist", path.display());
+        return None;
+    } else if !path.exists() {
         let file = fs::File::create(&path).ok()?;
 
         if cfg!(target_os = "linux") {
@@ -87,7 +199,12 @@ pub fn get_config() -> Option<Config> {
 
         fs::write(&path, serde_json::to_string_pretty(&Config {
             use_truenas: false, truenas_address: Some(url::Url::parse("http://localhost/").ok()?), truenas_token: Some("".to_string()),
-            accept_invalid_certs: false, port: 30603
+            accept_invalid_certs: false, truenas_instances: Vec::new(), truenas_websocket: default_truenas_websocket(), port: 30603, temperature_warning: None, temperature_critical: None,
+            caution_rules: smart::default_caution_rules(), poll_interval: default_poll_interval(),
+            standby_check: smart::StandbyCheck::default(), history_database: default_history_database(),
+            history_retention_days: default_history_retention(), prediction_window_days: default_prediction_window(), influxdb: None, graphite: None,
+            notifications: notify::NotifyConfig::default(), api_keys: Vec::new(), tls: None,
+            listen: Vec::new(), unix_socket_mode: default_unix_socket_mode()
         }).ok()?.as_bytes()).ok()?;
     } else if path.is_dir() {
         return None;
@@ -103,7 +220,54 @@ pub fn get_config() -> Option<Config> {
     }
     
 
-    Some(serde_json::from_slice(fs::read(&path).ok()?.as_slice()).ok()?)
+    let mut config: Config = match serde_json::from_slice(fs::read(&path).ok()?.as_slice()) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Config file {} is invalid: {}", path.display(), e);
+            return None;
+        }
+    };
+
+    if let (Some(database), Some(dir)) = (&config.history_database, path.parent()) {
+        config.history_database = Some(dir.join(database).to_string_lossy().to_string());
+    }
+    if let (Some(tls), Some(dir)) = (config.tls.as_mut(), path.parent()) {
+        tls.resolve_paths(dir);
+    }
+
+    if config.history_retention_days != 0 && config.history_retention_days < config.prediction_window_days * 2 {
+        error!("history_retention_days has to be at least twice prediction_window_days, as the trends compare the last two windows");
+        return None;
+    }
+
+    if config.poll_interval == 0 && config.notifications.enabled() {
+        error!("Notifications require background polling, set poll_interval above 0 or remove the notification targets");
+        return None;
+    }
+
+    if let Err(e) = truenas::validate_instances(&truenas::configured_instances(&config)) {
+        error!("{}", e);
+        return None;
+    }
+
+    if let Err(e) = auth::validate_api_keys(&config.api_keys) {
+        error!("{}", e);
+        return None;
+    }
+
+    Some(config)
+}
+
+/// Config with only the required fields set, everything else at its default
+#[cfg(test)]
+pub fn test_config() -> Config {
+    serde_json::from_str(r#"{
+        "use_truenas": false,
+        "truenas_address": null,
+        "truenas_token": null,
+        "accept_invalid_certs": false,
+        "port": 30603
+    }"#).expect("test config is valid")
 }
 
 #[derive(Debug, Parser)]
@@ -113,5 +277,8 @@ struct Args {
     install: bool,
 
     #[arg(short, long, help = "Where the config file is located")]
-    config: Option<String>
+    config: Option<String>,
+
+    #[arg(short = 'k', long, help = "Generates a new api key and prints it with the hash to put into the config")]
+    generate_key: bool
 } 
\ No newline at end of file
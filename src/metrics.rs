@@ -0,0 +1,232 @@
+use std::fmt::Write;
+
+use crate::data::{Alert, AlertLevel, Blockdevice, Smart};
+
+// Renders the collected data in the Prometheus text exposition format
+
+/// Samples are grouped by metric name, as the format requires all samples of a metric to follow its HELP and TYPE lines
+#[derive(Default)]
+struct Metrics {
+    families: Vec<Family>
+}
+
+struct Family {
+    name: &'static str,
+    help: &'static str,
+    samples: Vec<(String, f64)>
+}
+
+impl Metrics {
+    fn add(&mut self, name: &'static str, help: &'static str, labels: String, value: f64) {
+        match self.families.iter_mut().find(|family| family.name == name) {
+            Some(family) => family.samples.push((labels, value)),
+            None => self.families.push(Family {
+                name,
+                help,
+                samples: vec![(labels, value)]
+            })
+        }
+    }
+
+    fn render(self) -> String {
+        let mut out = String::new();
+        for family in self.families {
+            let _ = writeln!(out, "# HELP {} {}", family.name, family.help);
+            let _ = writeln!(out, "# TYPE {} gauge", family.name);
+            for (labels, value) in family.samples {
+                if labels.is_empty() {
+                    let _ = writeln!(out, "{} {}", family.name, value);
+                } else {
+                    let _ = writeln!(out, "{}{{{}}} {}", family.name, labels, value);
+                }
+            }
+        }
+
+        out
+    }
+}
+
+/// `smart` is the drive name with its data, `truenas` has for each TrueNAS instance the name, the ping result and alerts (if they could be read)
+pub fn render(disks: &[Blockdevice], smart: &[(String, Smart)], truenas: &[(String, bool, Option<Vec<Alert>>)]) -> String {
+    let mut metrics = Metrics::default();
+
+    for (name, data) in smart {
+        let disk = disks.iter().find(|disk| &disk.name == name);
+        let labels = format!(
+            "name=\"{}\",disk_id=\"{}\",serial=\"{}\",model=\"{}\"",
+            escape(name),
+            escape(disk.and_then(|disk| disk.disk_id.as_deref()).unwrap_or_default()),
+            escape(disk.and_then(|disk| disk.serial.as_deref()).unwrap_or_default()),
+            escape(disk.and_then(|disk| disk.model.as_deref()).unwrap_or_default())
+        );
+
+        add_drive(&mut metrics, &labels, data);
+    }
+
+    for (instance, status, alerts) in truenas {
+        // Not called instance, as Prometheus sets that label to the scrape target
+        let instance_label = format!("truenas=\"{}\"", escape(instance));
+        metrics.add("rdm_truenas_status", "1 if the TrueNAS api answered the ping", instance_label.clone(), bool_value(*status));
+
+        if let Some(alerts) = alerts {
+            for (level, label) in [(AlertLevel::Info, "info"), (AlertLevel::Warning, "warning"), (AlertLevel::Critical, "critical"), (AlertLevel::Unknown, "unknown")] {
+                let count = alerts.iter().filter(|alert| !alert.dismissed && alert.level == level).count();
+                metrics.add("rdm_truenas_alerts", "Number of not dismissed TrueNAS alerts", format!("{},level=\"{}\"", instance_label, label), count as f64);
+            }
+        }
+    }
+
+    metrics.render()
+}
+
+fn add_drive(metrics: &mut Metrics, labels: &str, data: &Smart) {
+    metrics.add("rdm_smart_passed", "1 if the drive passed the smart overall health check", labels.to_string(), bool_value(data.passed));
+    metrics.add("rdm_smart_caution", "Caution level evaluated by the monitor, 0 good, 1 warning, 2 critical", labels.to_string(), data.caution.as_number() as f64);
+    metrics.add("rdm_smart_power_on_hours", "Power on hours of the drive", labels.to_string(), data.power_on_hours as f64);
+    metrics.add("rdm_smart_power_cycle_count", "Power cycles of the drive", labels.to_string(), data.power_cycle_count as f64);
+    metrics.add("rdm_smart_asleep", "1 if the drive was asleep at the last poll, then the other values are from the last time it was awake", labels.to_string(), bool_value(data.power_state.is_some()));
+    metrics.add("rdm_smart_sample_timestamp_seconds", "Unix time the smart data was read", labels.to_string(), data.datetime_ms as f64 / 1000.0);
+
+    if let Some(temp) = &data.temperature {
+        metrics.add("rdm_smart_temperature_celsius", "Current temperature of the drive", labels.to_string(), temp.current as f64);
+    }
+
+    if let Some(prediction) = &data.prediction {
+        metrics.add("rdm_smart_prediction_caution", "Caution level of the attribute trends, 0 good, 1 warning, 2 critical", labels.to_string(), prediction.caution.as_number() as f64);
+    }
+
+    for attribute in &data.attributes {
+        let labels = format!("{},id=\"{}\",attribute=\"{}\"", labels, attribute.id, escape(&attribute.name));
+        metrics.add("rdm_smart_attribute_value", "Normalized value of the smart attribute", labels.clone(), attribute.value as f64);
+        metrics.add("rdm_smart_attribute_worst", "Worst normalized value of the smart attribute", labels.clone(), attribute.worst as f64);
+        metrics.add("rdm_smart_attribute_threshold", "Vendor threshold of the normalized value", labels.clone(), attribute.threshold as f64);
+        metrics.add("rdm_smart_attribute_raw", "Raw value of the smart attribute", labels.clone(), attribute.raw as f64);
+        if let Some(decoded) = &attribute.raw_decoded {
+            metrics.add("rdm_smart_attribute_decoded", "Decoded raw value for attributes with vendor packed raw values", labels.clone(), decoded.value as f64);
+        }
+        metrics.add("rdm_smart_attribute_caution", "Caution level of the attribute, 0 good, 1 warning, 2 critical", labels, attribute.caution.as_number() as f64);
+    }
+
+    if let Some(nvme) = &data.nvme {
+        metrics.add("rdm_nvme_critical_warning", "Critical warning bitfield of the NVMe health log", labels.to_string(), nvme.critical_warning.value as f64);
+        metrics.add("rdm_nvme_available_spare_percent", "Remaining spare capacity", labels.to_string(), nvme.available_spare as f64);
+        metrics.add("rdm_nvme_available_spare_threshold_percent", "Threshold of the spare capacity", labels.to_string(), nvme.available_spare_threshold as f64);
+        metrics.add("rdm_nvme_percentage_used", "Estimate of the used up life time", labels.to_string(), nvme.percentage_used as f64);
+        metrics.add("rdm_nvme_data_units_read", "Data read in units of 512000 bytes", labels.to_string(), nvme.data_units_read as f64);
+        metrics.add("rdm_nvme_data_units_written", "Data written in units of 512000 bytes", labels.to_string(), nvme.data_units_written as f64);
+        metrics.add("rdm_nvme_unsafe_shutdowns", "Unsafe shutdowns", labels.to_string(), nvme.unsafe_shutdowns as f64);
+        metrics.add("rdm_nvme_media_errors", "Unrecovered data integrity errors", labels.to_string(), nvme.media_errors as f64);
+    }
+
+    if let Some(scsi) = &data.scsi {
+        metrics.add("rdm_scsi_grown_defect_list", "Defects added to the defect list since leaving the factory", labels.to_string(), scsi.grown_defect_list as f64);
+        for (operation, counter) in [("read", &scsi.read_errors), ("write", &scsi.write_errors), ("verify", &scsi.verify_errors)] {
+            if let Some(counter) = counter {
+                metrics.add("rdm_scsi_uncorrected_errors", "Uncorrected errors from the error counter log", format!("{},operation=\"{}\"", labels, operation), counter.total_uncorrected_errors as f64);
+            }
+        }
+    }
+}
+
+fn bool_value(value: bool) -> f64 {
+    if value { 1.0 } else { 0.0 }
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn disk() -> Blockdevice {
+        serde_json::from_str(r#"{
+            "name": "sda", "removable": false, "size_kb": 3907018584, "read_only": false, "type": "disk", "maj:min": "8:0",
+            "model": "WDC \"Red\" \\ Plus", "serial": "WD-WX12345678", "disk_id": "ata-WDC_WD40EFZX-68AWUN0_WD-WX12345678"
+        }"#).expect("disk parses")
+    }
+
+    fn smart() -> Smart {
+        serde_json::from_str(r#"{
+            "device": { "name": "/dev/sda", "type": "sat", "protocol": "ATA" },
+            "datetime_ms": 1700000000500,
+            "passed": true,
+            "power_on_hours": 12000,
+            "power_cycle_count": 42,
+            "attributes": [ {
+                "id": 5, "name": "Reallocated_Sector_Ct", "value": 100, "worst": 100, "threshold": 5, "raw": 8,
+                "flags": { "value": 51, "string": "PO--CK ", "prefailure": true, "updated_online": true, "performance": false, "error_rate": false, "event_count": true, "auto_keep": true },
+                "caution": "Critical"
+            } ],
+            "temperature": { "current": 34, "caution": "Good" },
+            "caution": "Critical",
+            "caution_rules": [ "Reallocated Sectors" ]
+        }"#).expect("smart parses")
+    }
+
+    fn alert(level: &str, dismissed: bool) -> Alert {
+        serde_json::from_str(&format!(r#"{{
+            "instance": "vm1", "uuid": "{}", "source": "", "klass": "SMART", "node": "A", "dismissed": {},
+            "formatted": "Device /dev/sdb is failing", "level": "{}", "one_shot": false, "datetime_ms": 0, "last_occurrence_ms": 0
+        }}"#, uuid::Uuid::new_v4(), dismissed, level)).expect("alert parses")
+    }
+
+    #[test]
+    fn drive_samples_are_labeled_and_escaped() {
+        let out = render(&[disk()], &[("sda".to_string(), smart())], &[]);
+        let labels = r#"name="sda",disk_id="ata-WDC_WD40EFZX-68AWUN0_WD-WX12345678",serial="WD-WX12345678",model="WDC \"Red\" \\ Plus""#;
+
+        assert!(out.contains(&format!("rdm_smart_passed{{{}}} 1\n", labels)));
+        assert!(out.contains(&format!("rdm_smart_caution{{{}}} 2\n", labels)));
+        assert!(out.contains(&format!("rdm_smart_power_on_hours{{{}}} 12000\n", labels)));
+        assert!(out.contains(&format!("rdm_smart_temperature_celsius{{{}}} 34\n", labels)));
+        assert!(out.contains(&format!("rdm_smart_sample_timestamp_seconds{{{}}} 1700000000.5\n", labels)));
+        assert!(out.contains(&format!("rdm_smart_attribute_raw{{{},id=\"5\",attribute=\"Reallocated_Sector_Ct\"}} 8\n", labels)));
+        assert!(out.contains(&format!("rdm_smart_attribute_caution{{{},id=\"5\",attribute=\"Reallocated_Sector_Ct\"}} 2\n", labels)));
+        assert!(!out.contains("rdm_nvme_"));
+        assert!(!out.contains("rdm_truenas_"));
+    }
+
+    #[test]
+    fn samples_follow_their_family() {
+        let mut second = smart();
+        second.passed = false;
+        let out = render(&[disk()], &[("sda".to_string(), smart()), ("sdb".to_string(), second)], &[]);
+
+        // Every family has exactly one HELP and TYPE line, directly followed by all of its samples
+        let lines: Vec<&str> = out.lines().collect();
+        let help = lines.iter().position(|line| *line == "# HELP rdm_smart_passed 1 if the drive passed the smart overall health check").expect("help line");
+        assert_eq!(lines.iter().filter(|line| line.starts_with("# HELP rdm_smart_passed ")).count(), 1);
+        assert_eq!(lines[help + 1], "# TYPE rdm_smart_passed gauge");
+        assert!(lines[help + 2].starts_with("rdm_smart_passed{name=\"sda\","));
+        assert!(lines[help + 3].starts_with("rdm_smart_passed{name=\"sdb\","));
+        assert!(lines[help + 3].ends_with(" 0"));
+        // Drives without lsblk data still get all labels
+        assert!(lines[help + 3].starts_with(r#"rdm_smart_passed{name="sdb",disk_id="",serial="",model=""}"#));
+        assert!(lines[help + 4].starts_with("# HELP "));
+    }
+
+    #[test]
+    fn truenas_status_and_alerts() {
+        let alerts = vec![alert("Critical", false), alert("Critical", true), alert("Warning", false)];
+        let out = render(&[], &[], &[("vm1".to_string(), true, Some(alerts)), ("vm\"2".to_string(), false, None)]);
+
+        assert!(out.contains("rdm_truenas_status{truenas=\"vm1\"} 1\n"));
+        assert!(out.contains("rdm_truenas_status{truenas=\"vm\\\"2\"} 0\n"));
+        // Dismissed alerts are not counted
+        assert!(out.contains("rdm_truenas_alerts{truenas=\"vm1\",level=\"critical\"} 1\n"));
+        assert!(out.contains("rdm_truenas_alerts{truenas=\"vm1\",level=\"warning\"} 1\n"));
+        assert!(out.contains("rdm_truenas_alerts{truenas=\"vm1\",level=\"info\"} 0\n"));
+        // Without alerts (like when the instance is down) there are no counts, instead of misleading zeros
+        assert!(!out.contains("truenas=\"vm\\\"2\",level="));
+    }
+
+    #[test]
+    fn escapes_label_values() {
+        assert_eq!(escape("plain"), "plain");
+        assert_eq!(escape("a\"b"), "a\\\"b");
+        assert_eq!(escape("a\\b"), "a\\\\b");
+        assert_eq!(escape("a\nb"), "a\\nb");
+    }
+}
@@ -0,0 +1,252 @@
+use std::fmt::Write;
+
+use lettre::{
+    message::{header::ContentType, Mailbox},
+    transport::smtp::{authentication::Credentials, client::{Tls, TlsParameters}},
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use log::error;
+use serde::{Deserialize, Serialize};
+
+use crate::data::{Blockdevice, CautionLevel, Smart};
+
+use super::Event;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct EmailTarget {
+    pub server: String,
+    /// Defaults to 25 without tls, 587 for starttls and 465 for tls
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub tls: EmailTls,
+    #[serde(default)]
+    pub accept_invalid_certs: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    pub from: String,
+    pub to: Vec<String>,
+    /// Sends an overview of all drives on top of the notifications
+    #[serde(default)]
+    pub digest: Digest
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EmailTls {
+    /// Plain text, only for relays on the same machine or network
+    None,
+    /// Upgrades the connection, fails if the server does not support it
+    #[default]
+    StartTls,
+    /// Implicit tls, also known as smtps
+    Tls
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Digest {
+    #[default]
+    Never,
+    Daily,
+    Weekly
+}
+
+impl Digest {
+    pub(super) fn interval_ms(&self) -> Option<u64> {
+        const DAY_MS: u64 = 24 * 60 * 60 * 1000;
+
+        match self {
+            Digest::Never => None,
+            Digest::Daily => Some(DAY_MS),
+            Digest::Weekly => Some(7 * DAY_MS)
+        }
+    }
+}
+
+impl EmailTarget {
+    pub(super) fn label(&self) -> String {
+        format!("email:{}", self.to.join(","))
+    }
+
+    pub(super) async fn send(&self, event: &Event) -> bool {
+        let mut body = format!("{}\n", event.message);
+        for (key, value) in [("Drive", &event.drive), ("Serial", &event.serial), ("Attributes", &event.attribute)] {
+            if let Some(value) = value {
+                let _ = write!(body, "\n{}: {}", key, value);
+            }
+        }
+
+        self.send_mail(format!("[{:?}] {}", event.level, event.title), body).await
+    }
+
+    /// Sends the health of all drives, `smart` is the drive name with its data
+    pub(super) async fn send_digest(&self, disks: &[Blockdevice], smart: &[(String, Smart)]) -> bool {
+        let not_good = smart.iter().filter(|(_, data)| !data.passed || data.caution != CautionLevel::Good).count();
+        let subject = format!("Drive health: {} drives, {} need attention", smart.len(), not_good);
+
+        let mut body = String::new();
+        for (name, data) in smart {
+            let disk = disks.iter().find(|disk| &disk.name == name);
+            let _ = writeln!(body, "{} - {} ({})",
+                name,
+                disk.and_then(|disk| disk.model.as_deref()).unwrap_or("unknown model"),
+                disk.and_then(|disk| disk.serial.as_deref()).unwrap_or("unknown serial"));
+            let _ = writeln!(body, "  Health check: {}", if data.passed { "passed" } else { "FAILED" });
+            let _ = writeln!(body, "  Caution: {:?}", data.caution);
+            if !data.caution_rules.is_empty() {
+                let _ = writeln!(body, "  Caused by: {}", data.caution_rules.join(", "));
+            }
+            if let Some(prediction) = &data.prediction {
+                let _ = writeln!(body, "  Trend: {:?}", prediction.caution);
+            }
+            if let Some(temp) = &data.temperature {
+                let _ = writeln!(body, "  Temperature: {}°C", temp.current);
+            }
+            let _ = writeln!(body, "  Power on hours: {}", data.power_on_hours);
+            if data.power_state.is_some() {
+                let _ = writeln!(body, "  Asleep, values are from the last time it was awake");
+            }
+            body.push('\n');
+        }
+
+        if smart.is_empty() {
+            body.push_str("No smart data was read\n");
+        }
+
+        self.send_mail(subject, body).await
+    }
+
+    async fn send_mail(&self, subject: String, body: String) -> bool {
+        match self.try_send_mail(subject, body).await {
+            Ok(()) => true,
+            Err(e) => {
+                error!("Failed to send email over {}: {}", self.server, e);
+                false
+            }
+        }
+    }
+
+    async fn try_send_mail(&self, subject: String, body: String) -> Result<(), String> {
+        let mut builder = Message::builder()
+            .from(self.from.parse::<Mailbox>().map_err(|e| e.to_string())?)
+            .subject(subject)
+            .header(ContentType::TEXT_PLAIN);
+        for to in &self.to {
+            builder = builder.to(to.parse::<Mailbox>().map_err(|e| e.to_string())?);
+        }
+        let message = builder.body(body).map_err(|e| e.to_string())?;
+
+        let parameters = || TlsParameters::builder(self.server.clone())
+            .dangerous_accept_invalid_certs(self.accept_invalid_certs)
+            .build()
+            .map_err(|e| e.to_string());
+        let (tls, port) = match self.tls {
+            EmailTls::None => (Tls::None, 25),
+            EmailTls::StartTls => (Tls::Required(parameters()?), 587),
+            EmailTls::Tls => (Tls::Wrapper(parameters()?), 465)
+        };
+
+        let mut transport = AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&self.server)
+            .port(self.port.unwrap_or(port))
+            .tls(tls);
+        if let (Some(username), Some(password)) = (&self.username, &self.password) {
+            transport = transport.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+
+        transport.build().send(message).await.map_err(|e| e.to_string())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{io::{AsyncBufReadExt, AsyncWriteExt, BufReader}, net::TcpListener};
+
+    use super::{Digest, EmailTarget, EmailTls};
+
+    /// Accepts one plain text SMTP session, answering `rcpt_reply` to RCPT TO, and returns everything the client sent
+    async fn smtp_sink(listener: TcpListener, rcpt_reply: &'static str) -> String {
+        let (stream, _) = listener.accept().await.expect("the client should connect");
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        let mut received = String::new();
+
+        writer.write_all(b"220 sink ESMTP\r\n").await.unwrap();
+        let mut in_data = false;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).await.unwrap() == 0 {
+                break;
+            }
+            received.push_str(&line);
+
+            let reply = if in_data {
+                if line != ".\r\n" {
+                    continue;
+                }
+                in_data = false;
+                "250 queued\r\n"
+            } else {
+                match line.get(..4).map(|command| command.to_ascii_uppercase()).as_deref() {
+                    Some("EHLO") => "250-sink\r\n250 8BITMIME\r\n",
+                    Some("RCPT") => rcpt_reply,
+                    Some("DATA") => {
+                        in_data = true;
+                        "354 go ahead\r\n"
+                    },
+                    Some("QUIT") => {
+                        writer.write_all(b"221 bye\r\n").await.unwrap();
+                        break;
+                    },
+                    _ => "250 ok\r\n"
+                }
+            };
+            writer.write_all(reply.as_bytes()).await.unwrap();
+        }
+
+        received
+    }
+
+    fn target(port: u16) -> EmailTarget {
+        EmailTarget {
+            server: "127.0.0.1".to_string(),
+            port: Some(port),
+            tls: EmailTls::None,
+            accept_invalid_certs: false,
+            username: None,
+            password: None,
+            from: "rdm@nas.lan".to_string(),
+            to: vec!["admin@nas.lan".to_string()],
+            digest: Digest::Never
+        }
+    }
+
+    #[tokio::test]
+    async fn sends_plain_mail_on_custom_port() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let sink = tokio::spawn(smtp_sink(listener, "250 ok\r\n"));
+
+        let res = target(port).try_send_mail("Drive sda failed".to_string(), "Health check failed".to_string()).await;
+        assert_eq!(res, Ok(()));
+
+        let received = sink.await.unwrap();
+        assert!(received.contains("MAIL FROM:<rdm@nas.lan>"));
+        assert!(received.contains("RCPT TO:<admin@nas.lan>"));
+        assert!(received.contains("Subject: Drive sda failed"));
+        assert!(received.contains("Health check failed"));
+    }
+
+    #[tokio::test]
+    async fn reports_rejected_recipient() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(smtp_sink(listener, "550 no such user\r\n"));
+
+        let res = target(port).try_send_mail("Test".to_string(), "Test".to_string()).await;
+        assert!(res.is_err());
+    }
+}
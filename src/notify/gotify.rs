@@ -0,0 +1,63 @@
+use log::error;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::data::AlertLevel;
+
+use super::Event;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct GotifyTarget {
+    pub server: Url,
+    /// Token of the application the messages are sent as
+    pub token: String
+}
+
+#[derive(Serialize)]
+struct Message<'a> {
+    title: &'a str,
+    message: &'a str,
+    priority: u8
+}
+
+impl GotifyTarget {
+    pub(super) fn label(&self) -> String {
+        format!("gotify:{}", self.server)
+    }
+
+    pub(super) async fn send(&self, client: &Client, event: &Event) -> bool {
+        let Ok(target) = self.server.join("message") else {
+            error!("Invalid gotify server {}", self.server);
+            return false;
+        };
+
+        // Gotify priorities go from 0 to 10, the android app only makes noise from 4 and pops up from 8
+        let priority = match event.level {
+            AlertLevel::Critical => 10,
+            AlertLevel::Warning => 7,
+            AlertLevel::Info | AlertLevel::Unknown => 4
+        };
+
+        let res = client.post(target)
+            .header("X-Gotify-Key", &self.token)
+            .json(&Message {
+                title: &event.title,
+                message: &event.message,
+                priority
+            })
+            .send().await;
+
+        match res {
+            Ok(res) if res.status().is_success() => true,
+            Ok(res) => {
+                error!("Failed to send notification to gotify {}: {}", self.server, res.status());
+                false
+            },
+            Err(e) => {
+                error!("Failed to send notification to gotify {}: {}", self.server, e);
+                false
+            }
+        }
+    }
+}
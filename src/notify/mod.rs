@@ -0,0 +1,382 @@
+mod email;
+mod gotify;
+mod ntfy;
+mod webhook;
+
+use std::{collections::{HashMap, HashSet}, sync::{Mutex, MutexGuard}};
+
+use log::{debug, error, info};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{data::{Alert, AlertLevel, Blockdevice, CautionLevel, NotificationResult, Smart}, history::{self, History}};
+
+pub use email::EmailTarget;
+pub use gotify::GotifyTarget;
+pub use ntfy::NtfyTarget;
+pub use webhook::WebhookTarget;
+
+// Watches the TrueNAS alerts and the smart state of the drives, and sends a notification
+// to all configured targets when something new comes up
+
+/// Name the notified alerts and drive states are stored under in the history database
+const STATE_NAME: &str = "notifier";
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct NotifyConfig {
+    #[serde(default)]
+    pub ntfy: Vec<NtfyTarget>,
+    #[serde(default)]
+    pub gotify: Vec<GotifyTarget>,
+    #[serde(default)]
+    pub webhooks: Vec<WebhookTarget>,
+    #[serde(default)]
+    pub email: Vec<EmailTarget>
+}
+
+impl NotifyConfig {
+    pub fn enabled(&self) -> bool {
+        !self.ntfy.is_empty() || !self.gotify.is_empty() || !self.webhooks.is_empty() || !self.email.is_empty()
+    }
+}
+
+/// Something worth notifying about, either a TrueNAS alert or a change of a drives health
+#[derive(Debug, Clone)]
+pub struct Event {
+    pub level: AlertLevel,
+    pub title: String,
+    pub message: String,
+    pub drive: Option<String>,
+    pub serial: Option<String>,
+    /// Names of the attributes that raised the caution
+    pub attribute: Option<String>,
+    /// Text of the TrueNAS alert
+    pub alert: Option<String>
+}
+
+pub struct Notifier {
+    config: NotifyConfig,
+    client: Client,
+    state: Mutex<State>
+}
+
+/// Kept in the history database (if enabled), so a restart does not notify about the same things again
+#[derive(Default, Clone, Deserialize, Serialize)]
+struct State {
+    /// Alerts we already notified about by TrueNAS instance
+    #[serde(default)]
+    alerts: HashMap<String, HashSet<Uuid>>,
+    /// Last known passed and caution by history key of the drive (see `history::drive_key`)
+    #[serde(default)]
+    drives: HashMap<String, (bool, CautionLevel)>,
+    /// Time the last digest was sent by index of the email target, the first is sent one interval after the start
+    #[serde(default)]
+    digests: HashMap<usize, u64>,
+    /// If the stored state was read already
+    #[serde(skip)]
+    loaded: bool
+}
+
+pub fn new_notifier(config: NotifyConfig) -> Notifier {
+    let notifier = Notifier {
+        config,
+        client: Client::new(),
+        state: Mutex::new(State::default())
+    };
+
+    if notifier.enabled() {
+        info!("Notifications enabled");
+    }
+
+    notifier
+}
+
+impl Notifier {
+    /// Creates a notifier with the new targets, which keeps the alerts and drive states already notified about
+    pub fn reconfigure(&self, config: NotifyConfig) -> Notifier {
+        let state = self.state.lock().expect("notifier state lock poisoned").clone();
+
+        Notifier {
+            config,
+            client: self.client.clone(),
+            state: Mutex::new(state)
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.config.enabled()
+    }
+
+    /// Locks the state, on first use the stored state is read from the history
+    fn state(&self, history: Option<&History>) -> MutexGuard<'_, State> {
+        let mut state = self.state.lock().expect("notifier state lock poisoned");
+
+        if let (false, Some(history)) = (state.loaded, history) {
+            state.loaded = true;
+
+            let stored = history.state(STATE_NAME)
+                .map_err(|e| e.to_string())
+                .and_then(|value| value.map(|value| serde_json::from_str::<State>(&value).map_err(|e| e.to_string())).transpose());
+            match stored {
+                Ok(Some(stored)) => {
+                    for (instance, alerts) in stored.alerts {
+                        state.alerts.entry(instance).or_default().extend(alerts);
+                    }
+                    for (drive, health) in stored.drives {
+                        state.drives.entry(drive).or_insert(health);
+                    }
+                    for (index, sent) in stored.digests {
+                        state.digests.entry(index).or_insert(sent);
+                    }
+                },
+                Ok(None) => (),
+                Err(e) => error!("Failed to read the notification state: {}", e)
+            }
+        }
+
+        state
+    }
+
+    fn save(state: &State, history: Option<&History>) {
+        let Some(history) = history else {
+            return;
+        };
+
+        let res = serde_json::to_string(state)
+            .map_err(|e| e.to_string())
+            .and_then(|value| history.set_state(STATE_NAME, &value).map_err(|e| e.to_string()));
+        if let Err(e) = res {
+            error!("Failed to store the notification state: {}", e);
+        }
+    }
+
+    /// Notifies about every alert of the instance that is not dismissed and was not notified about before
+    ///
+    /// The alerts notified about are kept in the history, if there is one
+    pub async fn alerts(&self, instance: &str, alerts: &[Alert], history: Option<&History>) {
+        if !self.enabled() {
+            return;
+        }
+
+        let events: Vec<Event> = {
+            let mut state = self.state(history);
+
+            // Forget alerts TrueNAS no longer lists, so the set does not grow forever
+            let known = state.alerts.entry(instance.to_string()).or_default();
+            let count = known.len();
+            known.retain(|uuid| alerts.iter().any(|alert| &alert.uuid == uuid));
+            let forgotten = known.len() != count;
+
+            let events: Vec<Event> = alerts.iter()
+                .filter(|alert| !alert.dismissed && known.insert(alert.uuid))
+                .map(|alert| Event {
+                    level: alert.level.clone(),
+                    title: format!("TrueNAS {} {}: {}", instance, alert.node, alert.klass),
+                    message: alert.text.clone(),
+                    drive: None,
+                    serial: None,
+                    attribute: None,
+                    alert: Some(alert.text.clone())
+                })
+                .collect();
+
+            if forgotten || !events.is_empty() {
+                Self::save(&state, history);
+            }
+            events
+        };
+
+        self.send(&events).await;
+    }
+
+    /// Notifies if a drive failed the health check or its caution was raised since the last call
+    ///
+    /// Drives seen for the first time only notify if they are already failing or not good
+    pub async fn smart(&self, disks: &[Blockdevice], smart: &[(String, Smart)], history: Option<&History>) {
+        if !self.enabled() {
+            return;
+        }
+
+        let events: Vec<Event> = {
+            let mut state = self.state(history);
+            let mut changed = false;
+
+            let events: Vec<Event> = smart.iter().filter_map(|(name, data)| {
+                let serial = disks.iter().find(|disk| &disk.name == name).and_then(|disk| disk.serial.clone());
+                let key = history::drive_key(serial.as_deref(), name);
+                let previous = state.drives.insert(key, (data.passed, data.caution));
+                changed |= previous != Some((data.passed, data.caution));
+                let (passed, caution) = previous.unwrap_or((true, CautionLevel::Good));
+
+                let label = match &serial {
+                    Some(serial) => format!("{} ({})", name, serial),
+                    None => name.clone()
+                };
+
+                if passed && !data.passed {
+                    Some(Event {
+                        level: AlertLevel::Critical,
+                        title: format!("Drive {} failed", name),
+                        message: format!("{} failed the smart overall health check", label),
+                        drive: Some(name.clone()),
+                        serial,
+                        attribute: None,
+                        alert: None
+                    })
+                } else if data.caution > caution {
+                    let attributes: Vec<&str> = data.attributes.iter()
+                        .filter(|attribute| attribute.caution != CautionLevel::Good)
+                        .map(|attribute| attribute.name.as_str())
+                        .collect();
+
+                    Some(Event {
+                        level: match data.caution {
+                            CautionLevel::Critical => AlertLevel::Critical,
+                            _ => AlertLevel::Warning
+                        },
+                        title: format!("Drive {}: {:?}", name, data.caution),
+                        message: format!("Caution of {} raised from {:?} to {:?}: {}", label, caution, data.caution, data.caution_rules.join(", ")),
+                        drive: Some(name.clone()),
+                        serial,
+                        attribute: (!attributes.is_empty()).then(|| attributes.join(", ")),
+                        alert: None
+                    })
+                } else {
+                    None
+                }
+            }).collect();
+
+            if changed {
+                Self::save(&state, history);
+            }
+            events
+        };
+
+        self.send(&events).await;
+    }
+
+    /// Sends the digest to every email target whose digest interval passed since the last one
+    pub async fn digest(&self, disks: &[Blockdevice], smart: &[(String, Smart)], now_ms: u64, history: Option<&History>) {
+        let due: Vec<&EmailTarget> = {
+            let mut state = self.state(history);
+            let mut changed = false;
+
+            let due: Vec<&EmailTarget> = self.config.email.iter().enumerate().filter(|(index, target)| {
+                let Some(interval) = target.digest.interval_ms() else {
+                    return false;
+                };
+
+                let last = *state.digests.entry(*index).or_insert_with(|| {
+                    changed = true;
+                    now_ms
+                });
+                if now_ms.saturating_sub(last) >= interval {
+                    state.digests.insert(*index, now_ms);
+                    changed = true;
+                    return true;
+                }
+                false
+            }).map(|(_, target)| target).collect();
+
+            if changed {
+                Self::save(&state, history);
+            }
+            due
+        };
+
+        for target in due {
+            debug!("Sending digest to {}", target.label());
+            target.send_digest(disks, smart).await;
+        }
+    }
+
+    /// Sends the digest to all email targets now, even those without a digest interval
+    pub async fn digest_now(&self, disks: &[Blockdevice], smart: &[(String, Smart)]) -> Vec<NotificationResult> {
+        let mut results = Vec::<NotificationResult>::new();
+        for target in &self.config.email {
+            results.push(NotificationResult { target: target.label(), success: target.send_digest(disks, smart).await });
+        }
+
+        results
+    }
+
+    /// Sends a sample event to all targets, returns if each of them accepted it
+    pub async fn test(&self) -> Vec<NotificationResult> {
+        let event = Event {
+            level: AlertLevel::Info,
+            title: "Test notification".to_string(),
+            message: "This is a test notification from the Restless Drive Monitor".to_string(),
+            drive: Some("sda".to_string()),
+            serial: Some("TEST-SERIAL".to_string()),
+            attribute: Some("Reallocated_Sector_Ct".to_string()),
+            alert: Some("This is a test alert".to_string())
+        };
+
+        self.send_event(&event).await
+    }
+
+    async fn send(&self, events: &[Event]) {
+        for event in events {
+            self.send_event(event).await;
+        }
+    }
+
+    async fn send_event(&self, event: &Event) -> Vec<NotificationResult> {
+        debug!("Sending notification: {}", event.title);
+        let mut results = Vec::<NotificationResult>::new();
+
+        for target in &self.config.ntfy {
+            results.push(NotificationResult { target: target.label(), success: target.send(&self.client, event).await });
+        }
+        for target in &self.config.gotify {
+            results.push(NotificationResult { target: target.label(), success: target.send(&self.client, event).await });
+        }
+        for target in &self.config.webhooks {
+            results.push(NotificationResult { target: target.label(), success: target.send(&self.client, event).await });
+        }
+        for target in &self.config.email {
+            results.push(NotificationResult { target: target.label(), success: target.send(event).await });
+        }
+
+        results
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::history::open_history;
+
+    #[test]
+    fn state_survives_a_restart() {
+        let history = open_history(Path::new(":memory:")).expect("in memory history opens");
+        let uuid = Uuid::new_v4();
+
+        let notifier = new_notifier(NotifyConfig::default());
+        {
+            let mut state = notifier.state(Some(&history));
+            state.alerts.entry("truenas".to_string()).or_default().insert(uuid);
+            state.drives.insert("WD-WCC7K1234567".to_string(), (true, CautionLevel::Warning));
+            state.digests.insert(0, 1700000000000);
+            Notifier::save(&state, Some(&history));
+        }
+
+        let restarted = new_notifier(NotifyConfig::default());
+        let state = restarted.state(Some(&history));
+        assert!(state.alerts.get("truenas").map(|alerts| alerts.contains(&uuid)).unwrap_or(false));
+        assert_eq!(state.drives.get("WD-WCC7K1234567"), Some(&(true, CautionLevel::Warning)));
+        assert_eq!(state.digests.get(&0), Some(&1700000000000));
+    }
+
+    #[test]
+    fn state_without_history_is_only_in_memory() {
+        let notifier = new_notifier(NotifyConfig::default());
+        notifier.state(None).drives.insert("sda".to_string(), (false, CautionLevel::Critical));
+
+        assert_eq!(notifier.state(None).drives.get("sda"), Some(&(false, CautionLevel::Critical)));
+        assert!(!notifier.state(None).loaded);
+    }
+}
@@ -0,0 +1,66 @@
+use log::error;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::data::AlertLevel;
+
+use super::Event;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct NtfyTarget {
+    #[serde(default = "default_server")]
+    pub server: Url,
+    pub topic: String,
+    /// Access token, if the topic is protected
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>
+}
+
+fn default_server() -> Url {
+    Url::parse("https://ntfy.sh/").expect("valid url")
+}
+
+impl NtfyTarget {
+    pub(super) fn label(&self) -> String {
+        format!("ntfy:{}", self.topic)
+    }
+
+    pub(super) async fn send(&self, client: &Client, event: &Event) -> bool {
+        let Ok(target) = self.server.join(&self.topic) else {
+            error!("Invalid ntfy topic {}", self.topic);
+            return false;
+        };
+
+        // ntfy priorities go from 1 (min) to 5 (max), 3 is the default
+        let priority = match event.level {
+            AlertLevel::Critical => 5,
+            AlertLevel::Warning => 4,
+            AlertLevel::Info | AlertLevel::Unknown => 3
+        };
+        let level = format!("{:?}", event.level).to_lowercase();
+        let tags: Vec<&str> = [Some(level.as_str()), event.drive.as_deref(), event.serial.as_deref()]
+            .into_iter().flatten().collect();
+
+        let mut req = client.post(target)
+            .header("Title", &event.title)
+            .header("Priority", priority.to_string())
+            .header("Tags", tags.join(","))
+            .body(event.message.clone());
+        if let Some(token) = &self.token {
+            req = req.bearer_auth(token);
+        }
+
+        match req.send().await {
+            Ok(res) if res.status().is_success() => true,
+            Ok(res) => {
+                error!("Failed to send notification to ntfy topic {}: {}", self.topic, res.status());
+                false
+            },
+            Err(e) => {
+                error!("Failed to send notification to ntfy topic {}: {}", self.topic, e);
+                false
+            }
+        }
+    }
+}
@@ -0,0 +1,188 @@
+use std::{collections::HashMap, time::Duration};
+
+use log::{debug, error};
+use reqwest::{Client, Method};
+use serde::{Deserialize, Serialize};
+
+use super::Event;
+
+/// Any http endpoint, like a Discord, Slack or Matrix webhook or a Home Assistant automation
+///
+/// The url and body can contain the placeholders `{{title}}`, `{{message}}`, `{{level}}`, `{{drive}}`, `{{serial}}`, `{{attribute}}` and `{{alert}}`,
+/// which are replaced with the values of the event (or an empty string)
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct WebhookTarget {
+    pub name: String,
+    pub url: String,
+    #[serde(default = "default_method")]
+    pub method: String,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Values are escaped for json strings if the content type contains json
+    #[serde(default = "default_content_type")]
+    pub content_type: String,
+    #[serde(default = "default_body")]
+    pub body: String,
+    /// Retries after a failed attempt, waiting 1, 2, 4... seconds in between
+    #[serde(default = "default_retries")]
+    pub retries: u32
+}
+
+fn default_method() -> String {
+    "POST".to_string()
+}
+
+fn default_content_type() -> String {
+    "application/json".to_string()
+}
+
+fn default_body() -> String {
+    "{\"title\": \"{{title}}\", \"message\": \"{{message}}\", \"level\": \"{{level}}\"}".to_string()
+}
+
+fn default_retries() -> u32 {
+    3
+}
+
+impl WebhookTarget {
+    pub(super) fn label(&self) -> String {
+        format!("webhook:{}", self.name)
+    }
+
+    pub(super) async fn send(&self, client: &Client, event: &Event) -> bool {
+        let Ok(method) = Method::from_bytes(self.method.to_uppercase().as_bytes()) else {
+            error!("Invalid method {} for webhook {}", self.method, self.name);
+            return false;
+        };
+
+        let json = self.content_type.contains("json");
+        let url = render(&self.url, event, |value| url::form_urlencoded::byte_serialize(value.as_bytes()).collect());
+        let body = render(&self.body, event, |value| if json { escape_json(value) } else { value.to_string() });
+
+        for attempt in 0..=self.retries {
+            if attempt != 0 {
+                tokio::time::sleep(Duration::from_secs(1 << (attempt - 1).min(6))).await;
+                debug!("Retrying webhook {} ({}/{})", self.name, attempt, self.retries);
+            }
+
+            let mut req = client.request(method.clone(), &url)
+                .header("Content-Type", &self.content_type)
+                .body(body.clone());
+            for (key, value) in &self.headers {
+                req = req.header(key, value);
+            }
+
+            match req.send().await {
+                Ok(res) if res.status().is_success() => return true,
+                // Client errors will not go away by retrying, except for rate limiting
+                Ok(res) if res.status().is_client_error() && res.status().as_u16() != 429 => {
+                    error!("Webhook {} rejected the notification: {}", self.name, res.status());
+                    return false;
+                },
+                Ok(res) => error!("Failed to send notification to webhook {}: {}", self.name, res.status()),
+                Err(e) => error!("Failed to send notification to webhook {}: {}", self.name, e)
+            }
+        }
+
+        false
+    }
+}
+
+fn render(template: &str, event: &Event, escape: impl Fn(&str) -> String) -> String {
+    let level = format!("{:?}", event.level);
+    let values = [
+        ("title", Some(event.title.as_str())),
+        ("message", Some(event.message.as_str())),
+        ("level", Some(level.as_str())),
+        ("drive", event.drive.as_deref()),
+        ("serial", event.serial.as_deref()),
+        ("attribute", event.attribute.as_deref()),
+        ("alert", event.alert.as_deref()),
+    ];
+
+    // A single pass over the template, so placeholders within the values (like in an alert text) are not replaced
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+
+        let placeholder = after.find("}}").and_then(|end| {
+            let key = &after[..end];
+            values.iter().find(|(name, _)| *name == key).map(|(_, value)| (end, value))
+        });
+        match placeholder {
+            Some((end, value)) => {
+                out.push_str(&escape(value.unwrap_or_default()));
+                rest = &after[end + 2..];
+            },
+            // Not one of ours, only skipping one brace so "{{{title}}}" still works
+            None => {
+                out.push('{');
+                rest = &rest[start + 1..];
+            }
+        }
+    }
+    out.push_str(rest);
+
+    out
+}
+
+fn escape_json(value: &str) -> String {
+    // Serialized as a json string, without the surrounding quotes
+    let quoted = serde_json::to_string(value).unwrap_or_default();
+    quoted[1..quoted.len() - 1].to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::AlertLevel;
+
+    fn event(message: &str) -> Event {
+        Event {
+            level: AlertLevel::Critical,
+            title: "Drive sda failed".to_string(),
+            message: message.to_string(),
+            drive: Some("sda".to_string()),
+            serial: None,
+            attribute: Some("Reallocated_Sector_Ct".to_string()),
+            alert: None
+        }
+    }
+
+    #[test]
+    fn replaces_placeholders() {
+        let out = render("{{title}}: {{message}} ({{level}}, {{drive}}, serial '{{serial}}', {{attribute}})", &event("sda failed"), str::to_string);
+        assert_eq!(out, "Drive sda failed: sda failed (Critical, sda, serial '', Reallocated_Sector_Ct)");
+    }
+
+    #[test]
+    fn placeholders_in_values_are_not_replaced() {
+        let out = render("{{message}} / {{drive}}", &event("contains {{drive}} and {{title}}"), str::to_string);
+        assert_eq!(out, "contains {{drive}} and {{title}} / sda");
+    }
+
+    #[test]
+    fn unknown_and_unclosed_placeholders_are_kept() {
+        let out = render("{{unknown}} {{drive}} {{{drive}}} {{ {{drive", &event(""), str::to_string);
+        assert_eq!(out, "{{unknown}} sda {sda} {{ {{drive");
+    }
+
+    #[test]
+    fn escapes_values_for_json() {
+        let target: WebhookTarget = serde_json::from_str(r#"{ "name": "test", "url": "http://localhost/" }"#).expect("target parses");
+        let body = render(&target.body, &event("line \"one\"\nback\\slash {{title}}"), escape_json);
+
+        let parsed: serde_json::Value = serde_json::from_str(&body).expect("body is valid json");
+        assert_eq!(parsed["message"], "line \"one\"\nback\\slash {{title}}");
+        assert_eq!(parsed["title"], "Drive sda failed");
+        assert_eq!(parsed["level"], "Critical");
+    }
+
+    #[test]
+    fn escapes_values_for_urls() {
+        let out = render("https://example.com/notify?text={{message}}&drive={{drive}}", &event("a&b=c d"), |value| url::form_urlencoded::byte_serialize(value.as_bytes()).collect());
+        assert_eq!(out, "https://example.com/notify?text=a%26b%3Dc+d&drive=sda");
+    }
+}
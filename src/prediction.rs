@@ -0,0 +1,269 @@
+use crate::{data::{AttributeTrend, CautionLevel, HistoryPoint, Prediction, Smart}, history::History};
+
+// Evaluates the stored history of the attributes that indicate a failing drive,
+// as a drive that went from 0 to 8 reallocated sectors in a week is worse then one that sat at 8 for years
+
+const DAY_MS: f64 = 24.0 * 60.0 * 60.0 * 1000.0;
+
+/// Days till the vendor threshold is reached under which caution is critical
+const CRITICAL_DAYS: f64 = 30.0;
+
+/// The attributes we evaluate, either the Ata attribute id or the name of a health value
+enum Tracked {
+    /// A counter that should stay at 0
+    Counter(&'static str),
+    /// The normalized value counts down towards the vendor threshold
+    AtaAttribute(u16),
+    /// Counts up towards 100
+    PercentageUsed,
+    /// Counts down towards the threshold
+    AvailableSpare
+}
+
+const TRACKED: [Tracked; 10] = [
+    Tracked::AtaAttribute(0x05), // Reallocated Sectors
+    Tracked::AtaAttribute(0xC5), // Current Pending Sectors
+    Tracked::AtaAttribute(0xC6), // Uncorrectable Sectors
+    Tracked::AtaAttribute(0xC7), // UDMA CRC Errors
+    Tracked::Counter("media_errors"),
+    Tracked::PercentageUsed,
+    Tracked::AvailableSpare,
+    Tracked::Counter("grown_defect_list"),
+    Tracked::Counter("read_uncorrected_errors"),
+    Tracked::Counter("write_uncorrected_errors"),
+];
+
+/// Evaluates the trends of the drive, `drive` is the key the history is stored under
+///
+/// Returns None if the history of the drive does not cover the window yet
+pub fn predict(history: &History, drive: &str, smart: &Smart, window_days: u64) -> Option<Prediction> {
+    let window_ms = window_days.max(1) * DAY_MS as u64;
+
+    let trends: Vec<AttributeTrend> = TRACKED.iter()
+        .filter_map(|tracked| evaluate(tracked, history, drive, smart, window_ms))
+        .collect();
+
+    if trends.is_empty() {
+        return None;
+    }
+
+    Some(Prediction {
+        window_days,
+        caution: trends.iter().map(|trend| trend.caution).max().unwrap_or_default(),
+        trends
+    })
+}
+
+fn evaluate(tracked: &Tracked, history: &History, drive: &str, smart: &Smart, window_ms: u64) -> Option<AttributeTrend> {
+    let (attribute, name) = match tracked {
+        Tracked::AtaAttribute(id) => (id.to_string(), smart.attributes.iter().find(|item| item.id == *id)?.name.clone()),
+        Tracked::Counter(name) => (name.to_string(), name.to_string()),
+        Tracked::PercentageUsed => ("percentage_used".to_string(), "percentage_used".to_string()),
+        Tracked::AvailableSpare => ("available_spare".to_string(), "available_spare".to_string())
+    };
+
+    let now = smart.datetime_ms;
+    let points = history.attribute_history(drive, &attribute, now.saturating_sub(window_ms * 2)).ok()??.points;
+
+    let raw = |point: &HistoryPoint| point.raw as f64;
+    let rate_per_day = rate(&points, now.saturating_sub(window_ms), now, raw)?;
+    let previous_rate_per_day = rate(&points, now.saturating_sub(window_ms * 2), now.saturating_sub(window_ms), raw);
+    let current = points.last()?.raw;
+
+    let days_to_threshold = match tracked {
+        Tracked::AtaAttribute(id) => {
+            let item = smart.attributes.iter().find(|item| item.id == *id)?;
+            let value = |point: &HistoryPoint| point.value.unwrap_or_default() as f64;
+            rate(&points, now.saturating_sub(window_ms), now, value)
+                .and_then(|rate| days_till(item.value as f64 - item.threshold as f64, -rate))
+        },
+        Tracked::PercentageUsed => days_till(100.0 - current as f64, rate_per_day),
+        Tracked::AvailableSpare => {
+            let threshold = smart.nvme.as_ref()?.available_spare_threshold as f64;
+            days_till(current as f64 - threshold, -rate_per_day)
+        },
+        Tracked::Counter(_) => None
+    };
+
+    // Percentage used grows during normal use, for the others growth (or shrinking for the spare) is a bad sign
+    let bad_growth = |rate: f64| match tracked {
+        Tracked::PercentageUsed => 0.0,
+        Tracked::AvailableSpare => -rate,
+        _ => rate
+    };
+    let growth = bad_growth(rate_per_day);
+    // Only growth that was already there before can accelerate, otherwise the first increment
+    // after years without change (like a single CRC error from a loose cable) would be critical
+    let accelerating = growth > 0.0 && previous_rate_per_day
+        .map(bad_growth)
+        .map(|previous| previous > 0.0 && growth > previous * 2.0)
+        .unwrap_or(false);
+
+    let mut caution = CautionLevel::Good;
+    if growth > 0.0 {
+        caution = CautionLevel::Warning;
+    }
+    if accelerating || days_to_threshold.map(|days| days < CRITICAL_DAYS).unwrap_or(false) {
+        caution = CautionLevel::Critical;
+    }
+
+    Some(AttributeTrend {
+        attribute,
+        name,
+        current,
+        rate_per_day,
+        previous_rate_per_day,
+        accelerating,
+        days_to_threshold,
+        caution
+    })
+}
+
+/// Change per day between start and end, None if the history does not cover the whole window
+///
+/// A history that only started within the window would be divided by the wrong number of days,
+/// so a drive that got a few errors on its first day would look like it is failing rapidly
+fn rate(points: &[HistoryPoint], start: u64, end: u64, value: impl Fn(&HistoryPoint) -> f64) -> Option<f64> {
+    let first = points.first()?;
+    if first.datetime_ms > start || end <= start {
+        return None;
+    }
+
+    let days = (end - start) as f64 / DAY_MS;
+
+    let at = |time: u64| points.iter().take_while(|point| point.datetime_ms <= time).last().map(&value);
+    Some((at(end)? - at(start)?) / days)
+}
+
+/// Days until the remaining distance is used up at this rate
+fn days_till(remaining: f64, rate_per_day: f64) -> Option<f64> {
+    if rate_per_day > 0.0 {
+        Some((remaining / rate_per_day).max(0.0))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::{data::{ScsiHealth, SmartDevice}, history::open_history};
+
+    const NOW: u64 = 1000 * DAY_MS as u64;
+
+    fn days_ago(days: u64) -> u64 {
+        NOW - days * DAY_MS as u64
+    }
+
+    fn scsi(datetime_ms: u64, grown_defect_list: u64) -> Smart {
+        Smart {
+            device: SmartDevice { name: "/dev/sdb".to_string(), device_type: "scsi".to_string(), protocol: "SCSI".to_string() },
+            datetime_ms,
+            power_state: None,
+            passed: true,
+            power_on_hours: 0,
+            power_cycle_count: 0,
+            attributes: Vec::new(),
+            nvme: None,
+            scsi: Some(ScsiHealth {
+                grown_defect_list,
+                grown_defect_list_increased: false,
+                read_errors: None,
+                write_errors: None,
+                verify_errors: None,
+                start_stop_cycles: None
+            }),
+            temperature: None,
+            error_log: None,
+            self_tests: None,
+            caution: CautionLevel::Good,
+            caution_rules: Vec::new(),
+            prediction: None
+        }
+    }
+
+    /// Stores the grown defect counts (days ago, count) and predicts at NOW with a 30 day window
+    fn predict_grown_defects(samples: &[(u64, u64)]) -> Option<AttributeTrend> {
+        let history = open_history(Path::new(":memory:")).expect("in memory history opens");
+        for (days, count) in samples {
+            history.store("drive", &scsi(days_ago(*days), *count)).expect("sample is stored");
+        }
+
+        let current = scsi(NOW, samples.last().map(|(_, count)| *count).unwrap_or_default());
+        history.store("drive", &current).expect("sample is stored");
+
+        predict(&history, "drive", &current, 30)
+            .and_then(|prediction| prediction.trends.into_iter().find(|trend| trend.attribute == "grown_defect_list"))
+    }
+
+    #[test]
+    fn short_history_has_no_rate() {
+        // Three defects within the first 10 days would be a rate of 0.3 per day if divided by the covered days
+        assert!(predict_grown_defects(&[(10, 0), (5, 3)]).is_none());
+    }
+
+    #[test]
+    fn window_without_previous_window() {
+        let trend = predict_grown_defects(&[(45, 0), (10, 3)]).expect("window is covered");
+
+        assert_eq!(trend.current, 3);
+        assert!((trend.rate_per_day - 0.1).abs() < 1e-9);
+        assert!(trend.previous_rate_per_day.is_none());
+        assert!(!trend.accelerating);
+        assert_eq!(trend.caution, CautionLevel::Warning);
+    }
+
+    #[test]
+    fn flat_series() {
+        let trend = predict_grown_defects(&[(90, 2)]).expect("window is covered");
+
+        assert_eq!(trend.current, 2);
+        assert_eq!(trend.rate_per_day, 0.0);
+        assert_eq!(trend.previous_rate_per_day, Some(0.0));
+        assert!(!trend.accelerating);
+        assert!(trend.days_to_threshold.is_none());
+        assert_eq!(trend.caution, CautionLevel::Good);
+    }
+
+    #[test]
+    fn accelerating_growth() {
+        // One defect in the previous window, four in the current one
+        let trend = predict_grown_defects(&[(90, 0), (45, 1), (10, 5)]).expect("window is covered");
+
+        assert!((trend.rate_per_day - 4.0 / 30.0).abs() < 1e-9);
+        assert!((trend.previous_rate_per_day.expect("previous window is covered") - 1.0 / 30.0).abs() < 1e-9);
+        assert!(trend.accelerating);
+        assert_eq!(trend.caution, CautionLevel::Critical);
+    }
+
+    #[test]
+    fn steady_growth_is_not_accelerating() {
+        let trend = predict_grown_defects(&[(90, 0), (45, 2), (10, 4)]).expect("window is covered");
+
+        assert!(!trend.accelerating);
+        assert_eq!(trend.caution, CautionLevel::Warning);
+    }
+
+    #[test]
+    fn first_growth_after_a_flat_window_is_not_accelerating() {
+        // The previous window had to grow too, else a single increment after years would be critical
+        let trend = predict_grown_defects(&[(365, 0), (10, 1)]).expect("window is covered");
+
+        assert_eq!(trend.previous_rate_per_day, Some(0.0));
+        assert!(!trend.accelerating);
+        assert_eq!(trend.caution, CautionLevel::Warning);
+    }
+
+    #[test]
+    fn rate_needs_the_whole_window() {
+        let point = |days: u64, raw: u64| HistoryPoint { datetime_ms: days_ago(days), value: None, worst: None, raw };
+        let points = vec![point(20, 0), point(10, 10)];
+        let raw = |point: &HistoryPoint| point.raw as f64;
+
+        assert!(rate(&points, days_ago(30), NOW, raw).is_none());
+        assert_eq!(rate(&points, days_ago(20), NOW, raw), Some(0.5));
+        assert_eq!(rate(&points, days_ago(15), days_ago(5), raw), Some(1.0));
+    }
+}
@@ -0,0 +1,177 @@
+use std::sync::Arc;
+
+use arc_swap::ArcSwap;
+use log::{error, info, warn};
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::{get_config, notify::{self, Notifier}, truenas, Config};
+
+// Everything that is derived from the config and can change on a reload (SIGHUP)
+// The api and the collector load the current settings on every use, so a reload swaps them all at once
+
+pub struct Settings {
+    pub config: Config,
+    /// The TrueNAS servers, empty if TrueNAS is disabled or not configured properly
+    truenas: Vec<truenas::Instance>,
+    pub notifier: Notifier
+}
+
+pub type SharedSettings = Arc<ArcSwap<Settings>>;
+
+/// Creates the settings from the config, `previous` passes on the state of the notifier, so alerts are not sent again
+pub fn new_settings(config: Config, previous: Option<&Settings>) -> Settings {
+    let notifier = match previous {
+        Some(previous) => previous.notifier.reconfigure(config.notifications.clone()),
+        None => notify::new_notifier(config.notifications.clone())
+    };
+
+    Settings {
+        truenas: truenas_instances(&config),
+        notifier,
+        config
+    }
+}
+
+fn truenas_instances(config: &Config) -> Vec<truenas::Instance> {
+    let configured = truenas::configured_instances(config);
+    if config.use_truenas && !configured.iter().any(|instance| instance.name == truenas::DEFAULT_INSTANCE) {
+        error!("No Address and or Token provided, no TrueNAS support for {}", truenas::DEFAULT_INSTANCE);
+    }
+
+    configured.iter().filter_map(|instance| {
+        match truenas::new_instance(instance) {
+            Some(instance) => {
+                info!("TrueNAS support enabled for {}!", instance.name);
+                Some(instance)
+            },
+            None => {
+                error!("Failed to create Web Client, no TrueNAS support for {}", instance.name);
+                None
+            }
+        }
+    }).collect()
+}
+
+impl Settings {
+    pub fn truenas_enabled(&self) -> bool {
+        !self.truenas.is_empty()
+    }
+
+    /// Returns all TrueNAS instances, empty if TrueNAS is disabled
+    pub fn truenas_instances(&self) -> &[truenas::Instance] {
+        &self.truenas
+    }
+
+    pub fn truenas_instance(&self, name: &str) -> Option<&truenas::Instance> {
+        self.truenas.iter().find(|instance| instance.name == name)
+    }
+}
+
+/// Reloads the config on SIGHUP, if the new config is invalid the current one stays in effect
+pub fn spawn_reload(settings: SharedSettings) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            error!("Failed to listen for SIGHUP, config reload is not available: {}", e);
+            return;
+        }
+    };
+
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            info!("Reloading config...");
+
+            // A missing file is an error here, a default config would drop the api keys of the running server
+            let Some(config) = get_config(false) else {
+                error!("Failed to parse config file, keeping the current config");
+                continue;
+            };
+
+            let current = settings.load();
+            let changed = restart_required(&current.config, &config);
+            if !changed.is_empty() {
+                warn!("Changes to {} only apply after a restart", changed.join(", "));
+            }
+
+            settings.store(Arc::new(new_settings(config, Some(&current))));
+            info!("Config reloaded");
+        }
+    });
+}
+
+/// Names of the changed fields that are only read at startup
+///
+/// The listeners are bound (with the tls paths) and the history database is opened once, everything else is read on every use
+fn restart_required(old: &Config, new: &Config) -> Vec<&'static str> {
+    let mut changed = Vec::new();
+    if new.listen != old.listen {
+        changed.push("listen");
+    }
+    if new.port != old.port {
+        changed.push("port");
+    }
+    if new.unix_socket_mode != old.unix_socket_mode {
+        changed.push("unix_socket_mode");
+    }
+    if new.tls != old.tls {
+        changed.push("tls");
+    }
+    if new.history_database != old.history_database {
+        changed.push("history_database");
+    }
+
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tls::TlsConfig;
+
+    fn tls(cert: &str) -> Option<TlsConfig> {
+        Some(TlsConfig { cert: cert.to_string(), key: "/etc/rdm/rdm_key.pem".to_string(), client_ca: None })
+    }
+
+    #[test]
+    fn reloadable_changes_need_no_restart() {
+        let old = crate::test_config();
+        let mut new = crate::test_config();
+        new.poll_interval = 60;
+        new.api_keys.clear();
+        new.use_truenas = true;
+
+        assert!(restart_required(&old, &new).is_empty());
+    }
+
+    #[test]
+    fn lists_every_restart_only_change() {
+        let mut old = crate::test_config();
+        old.tls = tls("/etc/rdm/rdm_cert.pem");
+        let mut new = crate::test_config();
+        new.tls = tls("/etc/ssl/nas.pem");
+        new.listen = vec!["[::]".to_string()];
+        new.port = 8443;
+        new.unix_socket_mode = "600".to_string();
+        new.history_database = None;
+
+        assert_eq!(restart_required(&old, &new), vec!["listen", "port", "unix_socket_mode", "tls", "history_database"]);
+    }
+
+    #[test]
+    fn any_tls_path_needs_a_restart() {
+        let mut old = crate::test_config();
+        old.tls = tls("/etc/rdm/rdm_cert.pem");
+
+        let mut new = old.clone();
+        new.tls.as_mut().expect("tls is set").client_ca = Some("/etc/rdm/ca.pem".to_string());
+        assert_eq!(restart_required(&old, &new), vec!["tls"]);
+
+        let mut new = old.clone();
+        new.tls.as_mut().expect("tls is set").key = "/etc/ssl/nas.key".to_string();
+        assert_eq!(restart_required(&old, &new), vec!["tls"]);
+
+        let mut new = old.clone();
+        new.tls = None;
+        assert_eq!(restart_required(&old, &new), vec!["tls"]);
+    }
+}
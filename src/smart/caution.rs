@@ -0,0 +1,414 @@
+use std::{collections::HashMap, sync::{Mutex, OnceLock}};
+
+use log::error;
+use regex::Regex;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{data::{self, CautionLevel}, history::{Baseline, History}};
+
+// Evaluating the caution of smart attributes based on the rules from the config
+
+/// A rule that raises caution when a smart attribute meets a condition
+///
+/// A rule applies to every attribute that matches all of the set filters (id, attribute_name, model and serial)
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CautionRule {
+    /// Reported in the list of rules that fired
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u16>,
+    /// Name of the attribute as reported by smartctl, like Reallocated_Sector_Ct
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attribute_name: Option<String>,
+    /// Regex the model name of the drive has to match
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<RulePattern>,
+    /// Regex the serial number of the drive has to match
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub serial: Option<RulePattern>,
+    #[serde(default)]
+    pub field: RuleField,
+    /// When true the change of the field since the drive was first read is compared instead of the absolute value
+    #[serde(default)]
+    pub delta: bool,
+    pub comparison: Comparison,
+    pub threshold: i64,
+    pub severity: RuleSeverity
+}
+
+/// Regex of a rule, compiled when the config is loaded, so invalid patterns are rejected right away
+#[derive(Debug, Clone)]
+pub struct RulePattern(Regex);
+
+impl RulePattern {
+    fn is_match(&self, value: &str) -> bool {
+        self.0.is_match(value)
+    }
+}
+
+impl Serialize for RulePattern {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.0.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for RulePattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let pattern = String::deserialize(deserializer)?;
+        Regex::new(&pattern)
+            .map(RulePattern)
+            .map_err(|e| serde::de::Error::custom(format!("invalid caution rule regex '{}': {}", pattern, e)))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleField {
+    #[default]
+    Raw,
+    /// The decoded raw value, falls back to the raw value for attributes that need no decoding
+    Decoded,
+    Value,
+    Worst
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Comparison {
+    Gt,
+    Ge,
+    Lt,
+    Le,
+    Eq,
+    Ne
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleSeverity {
+    /// Suppresses all other rules and the vendor threshold on the attribute while the condition is met, used to exempt certain drives
+    Ignore,
+    Warning,
+    Critical
+}
+
+/// These are the rules this programm used before they became configurable
+pub fn default_caution_rules() -> Vec<CautionRule> {
+    fn raw_above(name: &str, id: u16, threshold: i64, severity: RuleSeverity) -> CautionRule {
+        CautionRule {
+            name: name.to_string(),
+            id: Some(id),
+            attribute_name: None,
+            model: None,
+            serial: None,
+            field: RuleField::Raw,
+            delta: false,
+            comparison: Comparison::Gt,
+            threshold,
+            severity
+        }
+    }
+
+    // Read Error Rate (0x01) is not part of this, as older drives may just have an increase of these without signifying real errors
+    vec![
+        raw_above("Reallocated Sectors", 0x05, 0, RuleSeverity::Critical), // Anything larger then zero indicates imminent failure
+        raw_above("Spin Retries", 0x0A, 1, RuleSeverity::Warning),
+        raw_above("Reallocation Events", 0xC4, 0, RuleSeverity::Warning),
+        raw_above("Pending Sectors", 0xC5, 0, RuleSeverity::Warning),
+        raw_above("Uncorrectable Sectors", 0xC6, 0, RuleSeverity::Critical),
+    ]
+}
+
+/// Collects the highest caution level and the rules that raised it
+#[derive(Debug, Default)]
+pub(super) struct Caution {
+    pub(super) level: CautionLevel,
+    pub(super) rules: Vec<String>
+}
+
+impl Caution {
+    pub(super) fn raise(&mut self, level: CautionLevel, rule: &str) {
+        if level == CautionLevel::Good {
+            return;
+        }
+
+        if level > self.level {
+            self.level = level;
+        }
+        if !self.rules.iter().any(|item| item == rule) {
+            self.rules.push(rule.to_string());
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct AttributeValues {
+    raw: u64,
+    decoded: u64,
+    value: u8,
+    worst: u8
+}
+
+/// Attribute values of each drive (by its history key) when we first read it, used for delta rules
+///
+/// Loaded from the history database, so they survive restarts, this is only a cache of it
+fn attribute_baseline() -> &'static Mutex<HashMap<String, HashMap<u16, AttributeValues>>> {
+    static BASELINE: OnceLock<Mutex<HashMap<String, HashMap<u16, AttributeValues>>>> = OnceLock::new();
+    BASELINE.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// The stored baseline of the attribute, current becomes it if there is none, or if the history is disabled
+fn stored_baseline(history: Option<&History>, drive_key: &str, id: u16, current: AttributeValues) -> AttributeValues {
+    let Some(history) = history else {
+        return current;
+    };
+
+    let values = Baseline { value: Some(current.value), worst: Some(current.worst), raw: current.raw, decoded: current.decoded };
+    match history.baseline(drive_key, &id.to_string(), values) {
+        Ok(baseline) => AttributeValues {
+            raw: baseline.raw,
+            decoded: baseline.decoded,
+            value: baseline.value.unwrap_or(current.value),
+            worst: baseline.worst.unwrap_or(current.worst)
+        },
+        Err(e) => {
+            error!("Failed to read the baseline of attribute {} of {}: {}", id, drive_key, e);
+            current
+        }
+    }
+}
+
+/// Sets the caution of every attribute and raises the overall caution accordingly
+pub(super) fn evaluate_attributes(drive_key: &str, model: Option<&str>, serial: Option<&str>, attributes: &mut [data::SmartAttribute], rules: &[CautionRule], history: Option<&History>, caution: &mut Caution) {
+    let matches = |pattern: &Option<RulePattern>, value: Option<&str>| match (pattern, value) {
+        (None, _) => true,
+        (Some(pattern), Some(value)) => pattern.is_match(value),
+        (Some(_), None) => false
+    };
+    let rules: Vec<&CautionRule> = rules.iter()
+        .filter(|rule| matches(&rule.model, model) && matches(&rule.serial, serial))
+        .collect();
+
+    let mut baselines = attribute_baseline().lock().expect("attribute baseline lock poisoned");
+    let baselines = baselines.entry(drive_key.to_string()).or_default();
+
+    for attribute in attributes.iter_mut() {
+        let current = AttributeValues::from(&*attribute);
+        let baseline = *baselines.entry(attribute.id).or_insert_with(|| stored_baseline(history, drive_key, attribute.id, current));
+
+        let mut fired = Vec::<&CautionRule>::new();
+        for rule in &rules {
+            if rule.id.map(|id| id != attribute.id).unwrap_or(false)
+                || rule.attribute_name.as_ref().map(|name| !name.eq_ignore_ascii_case(&attribute.name)).unwrap_or(false) {
+                continue;
+            }
+
+            let value = if rule.delta {
+                rule.field.read(&current) - rule.field.read(&baseline)
+            } else {
+                rule.field.read(&current)
+            };
+
+            if rule.comparison.compare(value, rule.threshold) {
+                fired.push(rule);
+            }
+        }
+
+        // Ignore rules exempt the attribute entirely, as drives that report odd values from the factory often trip the vendor threshold as well
+        if fired.iter().any(|rule| rule.severity == RuleSeverity::Ignore) {
+            attribute.caution = CautionLevel::Good;
+            continue;
+        }
+
+        let mut level = CautionLevel::Good;
+        if attribute.worst <= attribute.threshold {
+            level = CautionLevel::Critical;
+            caution.raise(level, "Vendor Threshold");
+        }
+
+        for rule in fired {
+            let rule_level = rule.severity.level();
+            if rule_level > level {
+                level = rule_level;
+            }
+            caution.raise(rule_level, &rule.name);
+        }
+
+        attribute.caution = level;
+    }
+}
+
+impl From<&data::SmartAttribute> for AttributeValues {
+    fn from(attribute: &data::SmartAttribute) -> Self {
+        AttributeValues {
+            raw: attribute.raw,
+            decoded: attribute.raw_decoded.as_ref().map(|decoded| decoded.value).unwrap_or(attribute.raw),
+            value: attribute.value,
+            worst: attribute.worst
+        }
+    }
+}
+
+impl RuleField {
+    fn read(&self, values: &AttributeValues) -> i64 {
+        match self {
+            RuleField::Raw => i64::try_from(values.raw).unwrap_or(i64::MAX),
+            RuleField::Decoded => i64::try_from(values.decoded).unwrap_or(i64::MAX),
+            RuleField::Value => values.value as i64,
+            RuleField::Worst => values.worst as i64
+        }
+    }
+}
+
+impl Comparison {
+    fn compare(&self, value: i64, threshold: i64) -> bool {
+        match self {
+            Comparison::Gt => value > threshold,
+            Comparison::Ge => value >= threshold,
+            Comparison::Lt => value < threshold,
+            Comparison::Le => value <= threshold,
+            Comparison::Eq => value == threshold,
+            Comparison::Ne => value != threshold
+        }
+    }
+}
+
+impl RuleSeverity {
+    fn level(&self) -> CautionLevel {
+        match self {
+            RuleSeverity::Ignore => CautionLevel::Good,
+            RuleSeverity::Warning => CautionLevel::Warning,
+            RuleSeverity::Critical => CautionLevel::Critical
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::history::open_history;
+
+    fn attribute(id: u16, raw: u64) -> data::SmartAttribute {
+        data::SmartAttribute {
+            id,
+            name: "Test_Attribute".to_string(),
+            value: 100,
+            worst: 100,
+            threshold: 10,
+            raw,
+            raw_decoded: None,
+            flags: data::SmartFlags {
+                value: 0x32,
+                string: "-O--CK ".to_string(),
+                prefailure: false,
+                updated_online: true,
+                performance: false,
+                error_rate: false,
+                event_count: true,
+                auto_keep: true
+            },
+            caution: CautionLevel::Good
+        }
+    }
+
+    fn rule(json: &str) -> CautionRule {
+        serde_json::from_str(json).expect("rule parses")
+    }
+
+    fn evaluate(drive_key: &str, model: &str, attributes: &mut [data::SmartAttribute], rules: &[CautionRule], history: Option<&History>) -> Caution {
+        let mut caution = Caution::default();
+        evaluate_attributes(drive_key, Some(model), Some("SERIAL"), attributes, rules, history, &mut caution);
+        caution
+    }
+
+    #[test]
+    fn invalid_regex_is_rejected_on_load() {
+        let res = serde_json::from_str::<CautionRule>(r#"{ "name": "broken", "model": "^ST(4000", "comparison": "gt", "threshold": 0, "severity": "warning" }"#);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn rules_match_id_and_model() {
+        let rules = vec![
+            rule(r#"{ "name": "Seagate Reallocations", "id": 5, "model": "^ST", "comparison": "gt", "threshold": 0, "severity": "critical" }"#),
+            rule(r#"{ "name": "Pending", "attribute_name": "test_attribute", "comparison": "ge", "threshold": 2, "severity": "warning" }"#),
+        ];
+
+        let mut attributes = vec![attribute(5, 1), attribute(197, 1)];
+        let caution = evaluate("match-wd", "WDC WD40EFRX", &mut attributes, &rules, None);
+        // Model does not match the first rule, the second one matches by name (case insensitive) but is below the threshold
+        assert_eq!(caution.level, CautionLevel::Good);
+        assert!(caution.rules.is_empty());
+
+        let mut attributes = vec![attribute(5, 1), attribute(197, 2)];
+        let caution = evaluate("match-st", "ST4000NM0035", &mut attributes, &rules, None);
+        assert_eq!(caution.level, CautionLevel::Critical);
+        assert_eq!(caution.rules, vec!["Seagate Reallocations".to_string(), "Pending".to_string()]);
+        assert_eq!(attributes[0].caution, CautionLevel::Critical);
+        assert_eq!(attributes[1].caution, CautionLevel::Warning);
+    }
+
+    #[test]
+    fn delta_compares_against_the_first_reading() {
+        let history = open_history(Path::new(":memory:")).expect("in memory history opens");
+        let rules = vec![rule(r#"{ "name": "CRC Increase", "id": 199, "delta": true, "comparison": "gt", "threshold": 5, "severity": "warning" }"#)];
+
+        let mut attributes = vec![attribute(199, 100)];
+        let caution = evaluate("delta", "ST4000NM0035", &mut attributes, &rules, Some(&history));
+        assert_eq!(caution.level, CautionLevel::Good);
+
+        let mut attributes = vec![attribute(199, 105)];
+        let caution = evaluate("delta", "ST4000NM0035", &mut attributes, &rules, Some(&history));
+        assert_eq!(caution.level, CautionLevel::Good);
+
+        let mut attributes = vec![attribute(199, 106)];
+        let caution = evaluate("delta", "ST4000NM0035", &mut attributes, &rules, Some(&history));
+        assert_eq!(caution.level, CautionLevel::Warning);
+        assert_eq!(caution.rules, vec!["CRC Increase".to_string()]);
+
+        // The baseline is stored, so it survives the memory cache being cleared by a restart
+        attribute_baseline().lock().expect("attribute baseline lock poisoned").remove("delta");
+        let mut attributes = vec![attribute(199, 106)];
+        let caution = evaluate("delta", "ST4000NM0035", &mut attributes, &rules, Some(&history));
+        assert_eq!(caution.level, CautionLevel::Warning);
+    }
+
+    #[test]
+    fn ignore_suppresses_rules_and_vendor_threshold() {
+        let rules = vec![
+            rule(r#"{ "name": "Reallocation Events", "id": 196, "comparison": "gt", "threshold": 0, "severity": "warning" }"#),
+            rule(r#"{ "name": "Factory Reallocation Events", "id": 196, "model": "^ST4000", "comparison": "le", "threshold": 8, "severity": "ignore" }"#),
+        ];
+
+        let mut exempt = attribute(196, 8);
+        exempt.worst = 5;
+        let mut attributes = vec![exempt];
+        let caution = evaluate("ignore", "ST4000NM0035", &mut attributes, &rules, None);
+        assert_eq!(caution.level, CautionLevel::Good);
+        assert!(caution.rules.is_empty());
+        assert_eq!(attributes[0].caution, CautionLevel::Good);
+
+        // Once the ignore rule no longer matches the others apply again
+        let mut attributes = vec![attribute(196, 9)];
+        let caution = evaluate("ignore", "ST4000NM0035", &mut attributes, &rules, None);
+        assert_eq!(caution.level, CautionLevel::Warning);
+        assert_eq!(caution.rules, vec!["Reallocation Events".to_string()]);
+
+        // Other drives are not exempt
+        let mut attributes = vec![attribute(196, 8)];
+        let caution = evaluate("ignore-wd", "WDC WD40EFRX", &mut attributes, &rules, None);
+        assert_eq!(caution.level, CautionLevel::Warning);
+    }
+
+    #[test]
+    fn vendor_threshold_is_critical() {
+        let mut failing = attribute(1, 0);
+        failing.worst = 10;
+        let mut attributes = vec![failing];
+        let caution = evaluate("vendor", "ST4000NM0035", &mut attributes, &[], None);
+        assert_eq!(caution.level, CautionLevel::Critical);
+        assert_eq!(caution.rules, vec!["Vendor Threshold".to_string()]);
+    }
+}
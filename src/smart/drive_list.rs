@@ -52,8 +52,6 @@ pub fn get_blockdevices() -> Option<Vec<crate::data::Blockdevice>>{
 }
 
 pub fn get_disks() -> Option<Vec<crate::data::Blockdevice>> {
-    get_drive_id_list();
-
     Some(get_blockdevices()?
             .into_iter()
             .filter(|device| device.device_type.as_str() == "disk" )
@@ -71,15 +69,13 @@ pub fn get_drive_id_list() -> Option<Vec<(String, String)>> {
     let by_id_path = PathBuf::from("/dev/disk/by-id/");
     let content = fs::read_dir(&by_id_path).ok()?;
 
-    for item in content {
-        if let Ok(item) = item {
-            if let Ok(link) = fs::read_link(item.path()) {
-                let id = item.path().file_name().expect("drisk by-id has to have a file name").to_str().expect("Filename should be string string").to_string();
-                if let Ok(target) = fs::canonicalize(by_id_path.clone().join(link)) {
-                    list.push((id, target.to_str().expect("disk path has to be a string").to_string()));
-                }
-                
+    for item in content.flatten() {
+        if let Ok(link) = fs::read_link(item.path()) {
+            let id = item.path().file_name().expect("drisk by-id has to have a file name").to_str().expect("Filename should be string string").to_string();
+            if let Ok(target) = fs::canonicalize(by_id_path.clone().join(link)) {
+                list.push((id, target.to_str().expect("disk path has to be a string").to_string()));
             }
+            
         }
     }
 
@@ -88,22 +84,21 @@ pub fn get_drive_id_list() -> Option<Vec<(String, String)>> {
 
 impl Blocklist {
     fn parse(self) -> Vec<crate::data::Blockdevice> {
+        // Read once here, instead of for every device
+        let id_list = get_drive_id_list();
+
         self.blockdevices.into_iter().map(|item| {
-            item.parse()
+            item.parse(id_list.as_deref())
         }).collect()
     }
 }
 
 impl Blockdevice {
-    fn parse(self) -> crate::data::Blockdevice {
+    fn parse(self, id_list: Option<&[(String, String)]>) -> crate::data::Blockdevice {
         let mut text = self.size.replace(",", ".").to_lowercase();
         
         let size = if let Some(size_letter) = text.pop() {
-            let temp = if let Ok(try_size) = text.parse::<f64>() {
-                try_size
-            } else {
-                0.0
-            };
+            let temp = text.parse::<f64>().unwrap_or(0.0);
 
 
             let mut size = match size_letter {
@@ -122,23 +117,23 @@ impl Blockdevice {
         };
         
         let mut disk_id = None;
-        if let Some(list) =  get_drive_id_list() {
+        if let Some(list) = id_list {
             let this_drive = format!("/dev/{}",self.name);
 
 
             for (id, drive) in list {
 
-                if this_drive == drive {
+                if &this_drive == drive {
 
                     match &self.wwn {
                         Some(wwn) => {
-                            if wwn != &id {
-                                disk_id = Some(id);
+                            if wwn != id {
+                                disk_id = Some(id.clone());
                                 break;
                             }
                         },
                         None => {
-                            disk_id = Some(id);
+                            disk_id = Some(id.clone());
                             break;
                         }
                     }
@@ -146,13 +141,9 @@ impl Blockdevice {
             }
         }
 
-        let childs = if let Some(children) = self.children {
-            Some(children.into_iter().map(|item| {
-                item.parse()
-            }).collect())
-        } else {
-            None
-        };
+        let childs = self.children.map(|children| children.into_iter().map(|item| {
+            item.parse(id_list)
+        }).collect());
 
         crate::data::Blockdevice {
             name: self.name,
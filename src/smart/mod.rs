@@ -1,5 +1,10 @@
+mod caution;
 mod drive_list;
+mod raw_value;
+mod self_test;
 mod smart_read;
 
+pub use caution::{default_caution_rules, CautionRule, Comparison, RulePattern, RuleField, RuleSeverity};
 pub use drive_list::*;
+pub use self_test::*;
 pub use smart_read::*;
\ No newline at end of file
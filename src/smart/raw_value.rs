@@ -0,0 +1,163 @@
+use crate::data;
+
+// Vendors pack multiple values into the 6 byte raw value of certain attributes,
+// smartctl already formats most of them (with its drive database) into the raw string, so we decode that
+
+/// Returns None for attributes where the raw value is already a plain number
+pub(super) fn decode(id: u16, raw: u64, string: &str, seagate: bool) -> Option<data::SmartRawDecoded> {
+    match id {
+        0x09 => decode_power_on_time(raw, string),
+        0xBE | 0xC2 => decode_temperature(string),
+        // Seagate packs the error count into the upper 16 bits and the operation count into the lower 32 bits
+        0x01 | 0x07 | 0xC3 if seagate => Some(data::SmartRawDecoded {
+            value: (raw >> 32) & 0xFFFF,
+            errors: Some((raw >> 32) & 0xFFFF),
+            operations: Some(raw & 0xFFFF_FFFF),
+            ..decoded(raw, string)
+        }),
+        _ => {
+            // smartctl formatted the raw value into something else, like "0 (2 0)", so the leading number is the actual value
+            let value = leading_number(string)?;
+            if value != raw {
+                Some(data::SmartRawDecoded {
+                    value,
+                    ..decoded(raw, string)
+                })
+            } else {
+                None
+            }
+        }
+    }
+}
+
+/// Some drives count in minutes or half minutes, or pack milliseconds into the upper bytes,
+/// smartctl then formats the raw string as "12345h+12m+05.123s"
+fn decode_power_on_time(raw: u64, string: &str) -> Option<data::SmartRawDecoded> {
+    let string = string.trim();
+    let (hours, rest) = match string.split_once('h') {
+        Some((hours, rest)) => (hours.parse::<u64>().ok()?, rest),
+        None => {
+            let hours = leading_number(string)?;
+            return Some(data::SmartRawDecoded {
+                value: hours,
+                hours: Some(hours),
+                ..decoded(raw, string)
+            });
+        }
+    };
+
+    let mut minutes = None;
+    let mut seconds = None;
+    for part in rest.split('+').map(|part| part.trim()).filter(|part| !part.is_empty()) {
+        if let Some(min) = part.strip_suffix('m') {
+            minutes = min.parse::<u64>().ok();
+        } else if let Some(sec) = part.strip_suffix('s') {
+            seconds = sec.parse::<f64>().ok();
+        }
+    }
+
+    Some(data::SmartRawDecoded {
+        value: hours,
+        hours: Some(hours),
+        minutes,
+        seconds,
+        ..decoded(raw, string)
+    })
+}
+
+/// The raw string usually looks like "35 (Min/Max 20/45)" or "35 (0 18 0 0 0)"
+fn decode_temperature(string: &str) -> Option<data::SmartRawDecoded> {
+    let current = leading_number(string)?;
+
+    let (min, max) = match string.split_once("Min/Max") {
+        Some((_, range)) => {
+            let range = range.trim_start();
+            let (min, max) = range.split_once('/')?;
+            (min.trim().parse::<u64>().ok(), leading_number(max))
+        },
+        None => (None, None)
+    };
+
+    Some(data::SmartRawDecoded {
+        value: current,
+        min,
+        max,
+        ..decoded(current, string)
+    })
+}
+
+fn leading_number(string: &str) -> Option<u64> {
+    let string = string.trim_start();
+    let end = string.find(|c: char| !c.is_ascii_digit()).unwrap_or(string.len());
+    string[..end].parse::<u64>().ok()
+}
+
+fn decoded(raw: u64, string: &str) -> data::SmartRawDecoded {
+    data::SmartRawDecoded {
+        value: raw,
+        string: string.to_string(),
+        hours: None,
+        minutes: None,
+        seconds: None,
+        min: None,
+        max: None,
+        errors: None,
+        operations: None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::decode;
+
+    #[test]
+    fn power_on_time_with_minutes_and_seconds() {
+        let decoded = decode(0x09, 0x0C_0000_3039, "12345h+12m+05.123s", false).expect("power on time should be decoded");
+        assert_eq!(decoded.value, 12345);
+        assert_eq!(decoded.hours, Some(12345));
+        assert_eq!(decoded.minutes, Some(12));
+        assert_eq!(decoded.seconds, Some(5.123));
+    }
+
+    #[test]
+    fn power_on_time_plain_hours() {
+        let decoded = decode(0x09, 2000, "2000", false).expect("power on time should be decoded");
+        assert_eq!(decoded.hours, Some(2000));
+        assert_eq!(decoded.minutes, None);
+    }
+
+    #[test]
+    fn temperature_with_min_max() {
+        let decoded = decode(0xC2, 0x2D_0014_0023, "35 (Min/Max 20/45)", false).expect("temperature should be decoded");
+        assert_eq!(decoded.value, 35);
+        assert_eq!(decoded.min, Some(20));
+        assert_eq!(decoded.max, Some(45));
+    }
+
+    #[test]
+    fn temperature_without_min_max() {
+        let decoded = decode(0xBE, 0x12_0000_0023, "35 (0 18 0 0 0)", false).expect("temperature should be decoded");
+        assert_eq!(decoded.value, 35);
+        assert_eq!(decoded.min, None);
+    }
+
+    #[test]
+    fn seagate_packed_error_rate() {
+        // 3 errors in the upper 16 bits, 123456789 operations in the lower 32 bits
+        let raw = (3u64 << 32) | 123_456_789;
+        let decoded = decode(0x01, raw, &raw.to_string(), true).expect("seagate error rate should be decoded");
+        assert_eq!(decoded.value, 3);
+        assert_eq!(decoded.errors, Some(3));
+        assert_eq!(decoded.operations, Some(123_456_789));
+
+        // The same raw value of another vendor is a plain number
+        assert!(decode(0x01, raw, &raw.to_string(), false).is_none());
+    }
+
+    #[test]
+    fn formatted_raw_string() {
+        let decoded = decode(0xBB, 0x0002_0000_0000, "0 (2 0)", false).expect("formatted raw value should be decoded");
+        assert_eq!(decoded.value, 0);
+        assert!(decode(0x05, 8, "8", false).is_none());
+    }
+}
@@ -0,0 +1,219 @@
+use std::process::Command;
+
+use serde::Deserialize;
+
+use crate::data;
+
+use super::{SmartctlOutput, StandbyCheck};
+
+// Starting and reading the smart self-tests
+
+/// Starts a self-test on the drive, test_type can be short, long or conveyance
+///
+/// Returns None if smartctl could not be run, else if the drive accepted the test
+pub fn start_self_test(drive: String, test_type: &str) -> Option<bool> {
+    if cfg!(target_os = "windows") {
+        return None;
+    }
+
+    let output = Command::new("smartctl")
+        .arg("-j")
+        .arg("-t")
+        .arg(test_type)
+        .arg(format!("/dev/{}", drive))
+        .output().ok()?;
+
+    let res: SmartctlExit = serde_json::from_slice(output.stdout.as_slice()).ok()?;
+
+    // Bit 0-2 are set if the command line, opening the device or a smart command failed
+    Some(res.smartctl.exit_status & 0x07 == 0)
+}
+
+/// Reads the progress of a running self-test and the self-test log
+///
+/// Drives in a lower power mode then `standby_check` are not woken up, returns Unsupported if the drive has no ATA or NVMe self-test log
+pub fn get_self_test_status(drive: String, standby_check: StandbyCheck) -> Option<SelfTestReading> {
+    if cfg!(target_os = "windows") {
+        return None;
+    }
+
+    let output = Command::new("smartctl")
+        .arg("-j")
+        .arg("-n")
+        .arg(standby_check.arg())
+        .arg("-c")
+        .arg("-l")
+        .arg("selftest")
+        .arg(format!("/dev/{}", drive))
+        .output().ok()?;
+
+    if let Ok(res) = serde_json::from_slice::<SmartctlOutput>(output.stdout.as_slice()) {
+        if let Some(state) = res.smartctl.power_state() {
+            return Some(SelfTestReading::Asleep(state));
+        }
+    }
+
+    let exit: SmartctlExit = serde_json::from_slice(output.stdout.as_slice()).ok()?;
+    // Bit 0 and 1 are set if the command line was invalid or the device could not be opened
+    if exit.smartctl.exit_status & 0x03 != 0 {
+        return None;
+    }
+
+    let res: SelfTestResult = serde_json::from_slice(output.stdout.as_slice()).ok()?;
+    // SCSI drives (and most USB bridges) report their self-tests in a format we don't read
+    Some(res.parse().map(SelfTestReading::Status).unwrap_or(SelfTestReading::Unsupported))
+}
+
+pub enum SelfTestReading {
+    Status(data::SelfTestStatus),
+    /// The self-test log was not read, as it would have woken the drive up
+    Asleep(data::PowerState),
+    /// The drive has no self-test log we can read
+    Unsupported
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct SmartctlExit {
+    smartctl: SmartctlInfo
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct SmartctlInfo {
+    exit_status: u8
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct SelfTestResult {
+    ata_smart_data: Option<AtaSmartData>,
+    ata_smart_self_test_log: Option<AtaSelfTestLog>,
+    nvme_self_test_log: Option<NvmeSelfTestLog>
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct AtaSmartData {
+    self_test: Option<AtaSelfTest>
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct AtaSelfTest {
+    status: AtaSelfTestStatus
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct AtaSelfTestStatus {
+    value: u8,
+    string: String,
+    remaining_percent: Option<u8>,
+    passed: Option<bool>
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(super) struct AtaSelfTestLog {
+    standard: Option<AtaSelfTestTable>,
+    extended: Option<AtaSelfTestTable>
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct AtaSelfTestTable {
+    #[serde(default)]
+    table: Vec<AtaSelfTestEntry>
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct AtaSelfTestEntry {
+    #[serde(rename = "type")]
+    test_type: ValueString,
+    status: AtaSelfTestStatus,
+    lifetime_hours: u64,
+    lba: Option<u64>
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct ValueString {
+    value: u8,
+    string: String
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(super) struct NvmeSelfTestLog {
+    current_self_test_operation: ValueString,
+    current_self_test_completion_percent: Option<u8>,
+    #[serde(default)]
+    table: Vec<NvmeSelfTestEntry>
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct NvmeSelfTestEntry {
+    self_test_code: ValueString,
+    self_test_result: ValueString,
+    power_on_hours: u64,
+    lba: Option<u64>
+}
+
+impl SelfTestResult {
+    fn parse(self) -> Option<data::SelfTestStatus> {
+        if let Some(nvme) = self.nvme_self_test_log {
+            return Some(nvme.parse());
+        }
+
+        let status = self.ata_smart_data?.self_test?.status;
+        // Upper nibble of 0xF means a test is in progress, the lower nibble is the remaining progress in 10%
+        let running = status.value >> 4 == 0x0F;
+
+        Some(data::SelfTestStatus {
+            running,
+            progress_percent: if running { status.remaining_percent.map(|remaining| 100 - remaining) } else { None },
+            power_state: None,
+            status: status.string,
+            results: self.ata_smart_self_test_log.map(|log| log.parse()).unwrap_or_default()
+        })
+    }
+}
+
+impl AtaSelfTestLog {
+    pub(super) fn parse(self) -> Vec<data::SelfTestEntry> {
+        // The extended log is used by drives larger then what fits in 28bit LBA, so it is preferred
+        let table = match (self.extended, self.standard) {
+            (Some(log), _) => log.table,
+            (None, Some(log)) => log.table,
+            (None, None) => Vec::new()
+        };
+
+        table.into_iter().map(|item| {
+            data::SelfTestEntry {
+                test_type: item.test_type.string,
+                passed: item.status.passed.unwrap_or(false),
+                status: item.status.string,
+                lifetime_hours: item.lifetime_hours,
+                lba_of_first_error: item.lba
+            }
+        }).collect()
+    }
+}
+
+impl NvmeSelfTestLog {
+    fn parse(self) -> data::SelfTestStatus {
+        let running = self.current_self_test_operation.value != 0;
+        let results = self.parse_entries();
+
+        data::SelfTestStatus {
+            running,
+            progress_percent: if running { self.current_self_test_completion_percent } else { None },
+            power_state: None,
+            status: self.current_self_test_operation.string,
+            results
+        }
+    }
+
+    pub(super) fn parse_entries(&self) -> Vec<data::SelfTestEntry> {
+        self.table.iter().map(|item| {
+            data::SelfTestEntry {
+                test_type: item.self_test_code.string.clone(),
+                status: item.self_test_result.string.clone(),
+                passed: item.self_test_result.value == 0,
+                lifetime_hours: item.power_on_hours,
+                lba_of_first_error: item.lba
+            }
+        }).collect()
+    }
+}
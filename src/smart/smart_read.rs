@@ -1,36 +1,146 @@
-use std::process::Command;
+use std::{collections::HashMap, process::Command, sync::{Mutex, OnceLock}, time::{SystemTime, UNIX_EPOCH}};
 
-use serde::Deserialize;
+use log::error;
+use serde::{Deserialize, Serialize};
 
-use crate::data;
+use crate::{data::{self, CautionLevel}, history::{Baseline, History}, Config};
 
-pub fn get_smart(drive: String) -> Option<data::Smart> {
+use super::{caution::{self, Caution}, raw_value, self_test::{AtaSelfTestLog, NvmeSelfTestLog}};
+
+/// Reads the smart data of a drive
+///
+/// extended also reads the error, self-test and temperature history logs,
+/// the history keeps the baselines that changes are evaluated against, stored under `key` (see `history::drive_key`)
+pub fn get_smart(drive: String, extended: bool, config: &Config, key: &str, history: Option<&History>) -> Option<SmartReading> {
     if cfg!(target_os = "windows") {
         return None;
     }
 
-    let output = Command::new("smartctl")
-        .arg("-j")
+    let mut command = Command::new("smartctl");
+    command.arg("-j")
+        .arg("-n")
+        .arg(config.standby_check.arg())
         .arg("-H")
         .arg("-A")
+        // SCSI drives only print their error counter log with it
+        .arg("-l")
+        .arg("error");
+    if extended {
+        command.arg("-l")
+            .arg("selftest")
+            .arg("-l")
+            .arg("scttemp");
+    }
+
+    let output = command
         .arg(format!("/dev/{}", drive))
         .output().ok()?;
 
+    // When the drive is asleep smartctl exits before reading anything, and tells us why
+    if let Ok(res) = serde_json::from_slice::<SmartctlOutput>(output.stdout.as_slice()) {
+        if let Some(state) = res.smartctl.power_state() {
+            return Some(SmartReading::Asleep(state));
+        }
+    }
+
     if let Ok(res) = serde_json::from_slice(output.stdout.as_slice()) {
         let res: SmartResult = res;
 
-        return Some(res.parse());
+        return res.parse(config, key, history).map(|mut data| {
+            // The error log is read for every drive, but only returned in the extended mode
+            if !extended {
+                data.error_log = None;
+            }
+            SmartReading::Data(Box::new(data))
+        });
     }
 
     None
 }
 
+pub enum SmartReading {
+    Data(Box<data::Smart>),
+    /// The drive was not read, as it would have woken it up
+    Asleep(data::PowerState)
+}
+
+/// Power mode that smartctl won't wake the drive from, passed as `-n`
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum StandbyCheck {
+    /// Always read the drive, even if this spins it up
+    Never,
+    Sleep,
+    #[default]
+    Standby,
+    Idle
+}
+
+impl StandbyCheck {
+    pub(super) fn arg(&self) -> &'static str {
+        match self {
+            StandbyCheck::Never => "never",
+            StandbyCheck::Sleep => "sleep",
+            StandbyCheck::Standby => "standby",
+            StandbyCheck::Idle => "idle"
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(super) struct SmartctlOutput {
+    pub(super) smartctl: SmartctlInfo
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(super) struct SmartctlInfo {
+    #[serde(default)]
+    messages: Vec<SmartctlMessage>
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct SmartctlMessage {
+    string: String
+}
+
+impl SmartctlInfo {
+    /// Looks for the "Device is in STANDBY mode, exit(2)" message
+    pub(super) fn power_state(&self) -> Option<data::PowerState> {
+        self.messages.iter().find_map(|message| {
+            let mode = message.string.strip_prefix("Device is in ")?.split_whitespace().next()?;
+
+            if mode.starts_with("SLEEP") {
+                Some(data::PowerState::Sleep)
+            } else if mode.starts_with("STANDBY") {
+                Some(data::PowerState::Standby)
+            } else if mode.starts_with("IDLE") {
+                Some(data::PowerState::Idle)
+            } else {
+                None
+            }
+        })
+    }
+}
+
 #[derive(Debug, Clone, Deserialize)]
 struct SmartResult {
     smart_status: SmartStatus,
-    ata_smart_attributes: AttributeContainer,
+    ata_smart_attributes: Option<AttributeContainer>,
+    nvme_smart_health_information_log: Option<NvmeHealthLog>,
+    scsi_grown_defect_list: Option<u64>,
+    scsi_error_counter_log: Option<ScsiErrorCounterLog>,
+    scsi_start_stop_cycle_counter: Option<data::ScsiStartStopCounter>,
+    serial_number: Option<String>,
+    model_name: Option<String>,
+    model_family: Option<String>,
+    ata_smart_error_log: Option<AtaErrorLog>,
+    ata_smart_self_test_log: Option<AtaSelfTestLog>,
+    nvme_self_test_log: Option<NvmeSelfTestLog>,
+    temperature: Option<Temperature>,
+    ata_sct_temperature_history: Option<SctTemperatureHistory>,
+    #[serde(default)]
     power_cycle_count: u64,
-    power_on_time: PowerTime,
+    power_on_time: Option<PowerTime>,
     device: data::SmartDevice
 }
 
@@ -57,7 +167,8 @@ struct SmartAttribute {
 
 #[derive(Debug, Clone, Deserialize)]
 struct SmartRaw {
-    value: u64
+    value: u64,
+    string: String
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -65,65 +176,306 @@ struct PowerTime {
     hours: u64
 }
 
+#[derive(Debug, Clone, Deserialize)]
+struct NvmeHealthLog {
+    critical_warning: u8,
+    temperature: i32,
+    available_spare: u8,
+    available_spare_threshold: u8,
+    percentage_used: u8,
+    data_units_read: u64,
+    data_units_written: u64,
+    power_cycles: u64,
+    power_on_hours: u64,
+    unsafe_shutdowns: u64,
+    media_errors: u64,
+    num_err_log_entries: u64
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Temperature {
+    current: Option<i32>,
+    lifetime_min: Option<i32>,
+    lifetime_max: Option<i32>
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct SctTemperatureHistory {
+    logging_interval_minutes: u64,
+    #[serde(default)]
+    table: Vec<Option<i32>>
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct AtaErrorLog {
+    summary: AtaErrorLogSummary
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct AtaErrorLogSummary {
+    #[serde(default)]
+    count: u64,
+    #[serde(default)]
+    table: Vec<AtaErrorEntry>
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct AtaErrorEntry {
+    error_number: u64,
+    lifetime_hours: u64,
+    error_description: Option<String>,
+    completion_registers: Option<CompletionRegisters>
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct CompletionRegisters {
+    lba: Option<u64>
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct ScsiErrorCounterLog {
+    read: Option<data::ScsiErrorCounter>,
+    write: Option<data::ScsiErrorCounter>,
+    verify: Option<data::ScsiErrorCounter>
+}
+
+/// Grown defect count of the SCSI drive (by its history key) when we first read it
+///
+/// Kept in the history database so it survives restarts, only in memory if the history is disabled
+fn grown_defect_baseline(key: &str, current: u64, history: Option<&History>) -> u64 {
+    static BASELINE: OnceLock<Mutex<HashMap<String, u64>>> = OnceLock::new();
+
+    if let Some(history) = history {
+        let current = Baseline { value: None, worst: None, raw: current, decoded: current };
+        match history.baseline(key, "grown_defect_list", current) {
+            Ok(baseline) => return baseline.raw,
+            Err(e) => error!("Failed to read the grown defect baseline of {}: {}", key, e)
+        }
+    }
+
+    *BASELINE.get_or_init(|| Mutex::new(HashMap::new())).lock().expect("grown defect baseline lock poisoned")
+        .entry(key.to_string())
+        .or_insert(current)
+}
+
 impl SmartResult {
-    fn parse(self) -> data::Smart {
+    fn parse(self, config: &Config, key: &str, history: Option<&History>) -> Option<data::Smart> {
+        let power_on_hours = self.power_on_time.map(|time| time.hours).unwrap_or_default();
+        let datetime_ms = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_millis() as u64).unwrap_or_default();
 
-        let attributes = self.ata_smart_attributes.table.into_iter().map(|item| {
-            item.parse()
-        }).collect();
+        let mut caution = Caution::default();
 
-        let mut caution = false;
-        for item in &attributes {
-            let item: &data::SmartAttribute = item;
-            if item.caution {
-                caution = true;
-                break;
+        let nvme_temperature = self.nvme_smart_health_information_log.as_ref().map(|log| log.temperature);
+        let temperature = parse_temperature(self.temperature, self.ata_sct_temperature_history, nvme_temperature, config);
+        if let Some(temp) = &temperature {
+            caution.raise(temp.caution, "Temperature");
+        }
+
+        let error_log = self.ata_smart_error_log.map(|log| log.parse());
+        let self_tests = match (self.ata_smart_self_test_log, &self.nvme_self_test_log) {
+            (Some(log), _) => Some(log.parse()),
+            (None, Some(log)) => Some(log.parse_entries()),
+            (None, None) => None
+        };
+
+        if let Some(nvme) = self.nvme_smart_health_information_log {
+            let nvme = nvme.parse();
+            if nvme.critical_warning.value != 0 {
+                caution.raise(CautionLevel::Critical, "NVMe Critical Warning");
+            }
+            if nvme.available_spare < nvme.available_spare_threshold {
+                caution.raise(CautionLevel::Critical, "NVMe Available Spare");
             }
+            if nvme.percentage_used >= 100 {
+                caution.raise(CautionLevel::Warning, "NVMe Percentage Used");
+            }
+            if nvme.media_errors > 0 {
+                caution.raise(CautionLevel::Critical, "NVMe Media Errors");
+            }
+
+            return Some(data::Smart {
+                passed: self.smart_status.passed,
+                device: self.device,
+                datetime_ms,
+                power_state: None,
+                power_on_hours: nvme.power_on_hours,
+                power_cycle_count: nvme.power_cycles,
+                attributes: Vec::new(),
+                nvme: Some(nvme),
+                scsi: None,
+                temperature,
+                error_log,
+                self_tests,
+                caution: caution.level,
+                caution_rules: caution.rules,
+                prediction: None
+            });
+        }
+
+        if let Some(grown_defect_list) = self.scsi_grown_defect_list {
+            let baseline = grown_defect_baseline(key, grown_defect_list, history);
+
+            let (read_errors, write_errors, verify_errors) = match self.scsi_error_counter_log {
+                Some(log) => (log.read, log.write, log.verify),
+                None => (None, None, None)
+            };
+
+            let scsi = data::ScsiHealth {
+                grown_defect_list,
+                grown_defect_list_increased: grown_defect_list > baseline,
+                read_errors,
+                write_errors,
+                verify_errors,
+                start_stop_cycles: self.scsi_start_stop_cycle_counter
+            };
+
+            let uncorrected = |counter: &Option<data::ScsiErrorCounter>| {
+                counter.as_ref().map(|c| c.total_uncorrected_errors > 0).unwrap_or(false)
+            };
+            if scsi.grown_defect_list_increased {
+                caution.raise(CautionLevel::Warning, "SCSI Grown Defects");
+            }
+            if uncorrected(&scsi.read_errors) || uncorrected(&scsi.write_errors) {
+                caution.raise(CautionLevel::Critical, "SCSI Uncorrected Errors");
+            }
+
+            // SCSI has no power cycle attribute, the start-stop cycles are the closest thing
+            let power_cycle_count = scsi.start_stop_cycles.as_ref()
+                .and_then(|counter| counter.accumulated_start_stop_cycles)
+                .unwrap_or(self.power_cycle_count);
+
+            return Some(data::Smart {
+                passed: self.smart_status.passed,
+                device: self.device,
+                datetime_ms,
+                power_state: None,
+                power_on_hours,
+                power_cycle_count,
+                attributes: Vec::new(),
+                nvme: None,
+                scsi: Some(scsi),
+                temperature,
+                error_log,
+                self_tests,
+                caution: caution.level,
+                caution_rules: caution.rules,
+                prediction: None
+            });
         }
 
-        data::Smart {
+        let seagate = self.model_family.as_deref().map(|family| family.starts_with("Seagate")).unwrap_or(false)
+            || self.model_name.as_deref().map(|model| model.starts_with("ST")).unwrap_or(false);
+
+        let mut attributes: Vec<data::SmartAttribute> = self.ata_smart_attributes?.table.into_iter().map(|item| {
+            item.parse(seagate)
+        }).collect();
+
+        let power_on_hours = attributes.iter()
+            .find(|item| item.id == 0x09)
+            .and_then(|item| item.raw_decoded.as_ref())
+            .and_then(|decoded| decoded.hours)
+            .unwrap_or(power_on_hours);
+
+        caution::evaluate_attributes(key, self.model_name.as_deref(), self.serial_number.as_deref(), &mut attributes, &config.caution_rules, history, &mut caution);
+
+        Some(data::Smart {
             passed: self.smart_status.passed,
             device: self.device,
-            power_on_hours: self.power_on_time.hours,
+            datetime_ms,
+            power_state: None,
+            power_on_hours,
             power_cycle_count: self.power_cycle_count,
             attributes,
-            caution
+            nvme: None,
+            scsi: None,
+            temperature,
+            error_log,
+            self_tests,
+            caution: caution.level,
+            caution_rules: caution.rules,
+            prediction: None
+        })
+    }
+}
+
+fn parse_temperature(temperature: Option<Temperature>, history: Option<SctTemperatureHistory>, nvme_temperature: Option<i32>, config: &Config) -> Option<data::SmartTemperature> {
+    let (current, lifetime_min, lifetime_max) = match temperature {
+        Some(temp) => (temp.current.or(nvme_temperature)?, temp.lifetime_min, temp.lifetime_max),
+        None => (nvme_temperature?, None, None)
+    };
+
+    let reached = |threshold: Option<i32>| threshold.map(|threshold| current >= threshold).unwrap_or(false);
+    let caution = if reached(config.temperature_critical) {
+        CautionLevel::Critical
+    } else if reached(config.temperature_warning) {
+        CautionLevel::Warning
+    } else {
+        CautionLevel::Good
+    };
+
+    Some(data::SmartTemperature {
+        current,
+        lifetime_min,
+        lifetime_max,
+        history: history.map(|history| data::TemperatureHistory {
+            interval_minutes: history.logging_interval_minutes,
+            readings: history.table
+        }),
+        caution
+    })
+}
+
+impl AtaErrorLog {
+    fn parse(self) -> data::SmartErrorLog {
+        data::SmartErrorLog {
+            count: self.summary.count,
+            entries: self.summary.table.into_iter().map(|item| {
+                data::SmartErrorEntry {
+                    error_number: item.error_number,
+                    lifetime_hours: item.lifetime_hours,
+                    description: item.error_description,
+                    lba: item.completion_registers.and_then(|registers| registers.lba)
+                }
+            }).collect()
+        }
+    }
+}
+
+impl NvmeHealthLog {
+    fn parse(self) -> data::NvmeHealth {
+        let warning = self.critical_warning;
+
+        data::NvmeHealth {
+            critical_warning: data::NvmeCriticalWarning {
+                value: warning,
+                available_spare: warning & 0x01 != 0,
+                temperature: warning & 0x02 != 0,
+                reliability_degraded: warning & 0x04 != 0,
+                read_only: warning & 0x08 != 0,
+                volatile_memory_backup_failed: warning & 0x10 != 0,
+                persistent_memory_read_only: warning & 0x20 != 0
+            },
+            temperature: self.temperature,
+            available_spare: self.available_spare,
+            available_spare_threshold: self.available_spare_threshold,
+            percentage_used: self.percentage_used,
+            data_units_read: self.data_units_read,
+            data_units_written: self.data_units_written,
+            power_on_hours: self.power_on_hours,
+            power_cycles: self.power_cycles,
+            unsafe_shutdowns: self.unsafe_shutdowns,
+            media_errors: self.media_errors,
+            num_err_log_entries: self.num_err_log_entries
         }
     }
 }
 
 impl SmartAttribute {
-    fn parse(self) -> data::SmartAttribute {
+    fn parse(self, seagate: bool) -> data::SmartAttribute {
         let raw = self.raw.value;
+        let raw_decoded = raw_value::decode(self.id, raw, &self.raw.string, seagate);
 
-        let caution = if self.worst <= self.thresh {
-            true
-        } else {
-            match self.id {
-                0x01 => { // Read Error Rate
-                    // Older drives may just have an increase of these without signifying real errors, so for now we ignore
-                    false
-                },
-                0x05 => { // Reallocated Sectors Count
-                    raw > 0 // Anything larger then zero indicates imminent failure
-                },
-                0x0A => { // Spin Retry Count
-                    raw > 1
-                },
-                0xC4 => { // Reallocation Event Count
-                    raw > 0
-                },
-                0xC5 => { // Current Pending Sector Count
-                    raw > 0
-                },
-                0xC6 => { // Uncorrectable Sector Count
-                    raw > 0
-                },
-
-                _ => false,
-            }
-        };
-        
+        // Caution is evaluated afterwards with the rules from the config
         data::SmartAttribute {
             id: self.id,
             name: self.name,
@@ -131,8 +483,115 @@ impl SmartAttribute {
             worst: self.worst,
             threshold: self.thresh,
             raw,
+            raw_decoded,
             flags: self.flags,
-            caution
+            caution: CautionLevel::Good
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_fixture(json: &str, key: &str, history: Option<&History>) -> data::Smart {
+        let result: SmartResult = serde_json::from_str(json).expect("fixture parses");
+        result.parse(&crate::test_config(), key, history).expect("fixture has smart data")
+    }
+
+    #[test]
+    fn parses_nvme() {
+        let smart = parse_fixture(include_str!("fixtures/nvme.json"), "S4EWNX0R612345A", None);
+
+        assert!(smart.passed);
+        assert_eq!(smart.device.protocol, "NVMe");
+        assert_eq!(smart.power_on_hours, 13894);
+        assert_eq!(smart.power_cycle_count, 174);
+        assert!(smart.attributes.is_empty());
+        assert!(smart.scsi.is_none());
+        assert_eq!(smart.temperature.as_ref().map(|temp| temp.current), Some(38));
+
+        let nvme = smart.nvme.expect("nvme health is set");
+        assert_eq!(nvme.critical_warning.value, 0);
+        assert_eq!(nvme.available_spare, 100);
+        assert_eq!(nvme.available_spare_threshold, 10);
+        assert_eq!(nvme.percentage_used, 4);
+        assert_eq!(nvme.data_units_written, 38217290);
+        assert_eq!(nvme.unsafe_shutdowns, 39);
+        assert_eq!(nvme.media_errors, 0);
+        // Error log entries alone (often just invalid commands) are no reason for caution
+        assert_eq!(nvme.num_err_log_entries, 412);
+        assert_eq!(smart.caution, CautionLevel::Good);
+        assert!(smart.caution_rules.is_empty());
+
+        let self_tests = smart.self_tests.expect("nvme self-test log is set");
+        assert_eq!(self_tests.len(), 1);
+        assert_eq!(self_tests[0].lifetime_hours, 13880);
+    }
+
+    #[test]
+    fn nvme_media_errors_are_critical() {
+        let json = include_str!("fixtures/nvme.json").replace("\"media_errors\": 0", "\"media_errors\": 2");
+        let smart = parse_fixture(&json, "S4EWNX0R612345A", None);
+
+        assert_eq!(smart.caution, CautionLevel::Critical);
+        assert_eq!(smart.caution_rules, vec!["NVMe Media Errors".to_string()]);
+    }
+
+    #[test]
+    fn parses_sas() {
+        let smart = parse_fixture(include_str!("fixtures/sas.json"), "ZC1ABCDE0000C7301XYZ", None);
+
+        assert!(smart.passed);
+        assert_eq!(smart.device.protocol, "SCSI");
+        assert_eq!(smart.power_on_hours, 38211);
+        // SCSI drives have no power cycle count, the start-stop cycles are used instead
+        assert_eq!(smart.power_cycle_count, 62);
+        assert!(smart.attributes.is_empty());
+        assert!(smart.nvme.is_none());
+        assert_eq!(smart.temperature.as_ref().map(|temp| temp.current), Some(31));
+
+        let scsi = smart.scsi.expect("scsi health is set");
+        assert_eq!(scsi.grown_defect_list, 3);
+        assert!(!scsi.grown_defect_list_increased);
+        let read = scsi.read_errors.expect("read error counter is set");
+        assert_eq!(read.errors_corrected_by_eccfast, 1929384756);
+        assert_eq!(read.gigabytes_processed, "317436.287");
+        assert_eq!(read.total_uncorrected_errors, 0);
+        assert!(scsi.write_errors.is_some());
+        assert!(scsi.verify_errors.is_some());
+        let cycles = scsi.start_stop_cycles.expect("start-stop counter is set");
+        assert_eq!(cycles.year_of_manufacture.as_deref(), Some("2017"));
+        assert_eq!(cycles.accumulated_load_unload_cycles, Some(1417));
+
+        assert_eq!(smart.caution, CautionLevel::Good);
+    }
+
+    #[test]
+    fn sas_grown_defects_stay_latched() {
+        let history = crate::history::open_history(std::path::Path::new(":memory:")).expect("in memory history opens");
+        let sas = include_str!("fixtures/sas.json");
+        let grown = |count: u64| sas.replace("\"scsi_grown_defect_list\": 3", &format!("\"scsi_grown_defect_list\": {}", count));
+
+        let first = parse_fixture(&grown(3), "latched", Some(&history));
+        assert!(!first.scsi.expect("scsi health is set").grown_defect_list_increased);
+
+        let increased = parse_fixture(&grown(4), "latched", Some(&history));
+        assert!(increased.scsi.expect("scsi health is set").grown_defect_list_increased);
+        assert_eq!(increased.caution, CautionLevel::Warning);
+        assert_eq!(increased.caution_rules, vec!["SCSI Grown Defects".to_string()]);
+
+        // Compared against the first reading ever stored, not the previous one
+        let unchanged = parse_fixture(&grown(4), "latched", Some(&history));
+        assert!(unchanged.scsi.expect("scsi health is set").grown_defect_list_increased);
+        assert_eq!(unchanged.caution, CautionLevel::Warning);
+    }
+
+    #[test]
+    fn sas_uncorrected_errors_are_critical() {
+        let json = include_str!("fixtures/sas.json").replacen("\"total_uncorrected_errors\": 0", "\"total_uncorrected_errors\": 5", 1);
+        let smart = parse_fixture(&json, "ZC1ABCDE0000C7301XYZ", None);
+
+        assert_eq!(smart.caution, CautionLevel::Critical);
+        assert_eq!(smart.caution_rules, vec!["SCSI Uncorrected Errors".to_string()]);
+    }
+}
@@ -0,0 +1,125 @@
+use std::{fs, io, path::Path, time::Duration};
+
+use futures_util::Stream;
+use log::{error, info};
+use poem::listener::{RustlsCertificate, RustlsConfig};
+use serde::{Deserialize, Serialize};
+
+// Serves the api over https, reloading the certificate when it changes on disk (for example after a renewal)
+
+/// How often the certificate files are checked for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct TlsConfig {
+    /// Path of the certificate chain (pem), relative to the config file
+    #[serde(default = "default_cert")]
+    pub cert: String,
+    /// Path of the private key (pem), relative to the config file
+    #[serde(default = "default_key")]
+    pub key: String,
+    /// Path of the CA certificate (pem) client certificates are verified against, if set only clients with a valid certificate are accepted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_ca: Option<String>
+}
+
+fn default_cert() -> String {
+    "rdm_cert.pem".to_string()
+}
+
+fn default_key() -> String {
+    "rdm_key.pem".to_string()
+}
+
+impl TlsConfig {
+    pub fn resolve_paths(&mut self, dir: &Path) {
+        self.cert = dir.join(&self.cert).to_string_lossy().to_string();
+        self.key = dir.join(&self.key).to_string_lossy().to_string();
+        if let Some(ca) = &self.client_ca {
+            self.client_ca = Some(dir.join(ca).to_string_lossy().to_string());
+        }
+    }
+}
+
+/// The content of the files, to detect changes
+#[derive(PartialEq)]
+struct Files {
+    cert: Vec<u8>,
+    key: Vec<u8>,
+    client_ca: Option<Vec<u8>>
+}
+
+fn read_files(config: &TlsConfig) -> io::Result<Files> {
+    Ok(Files {
+        cert: fs::read(&config.cert)?,
+        key: fs::read(&config.key)?,
+        client_ca: config.client_ca.as_ref().map(fs::read).transpose()?
+    })
+}
+
+fn rustls_config(files: &Files) -> RustlsConfig {
+    let config = RustlsConfig::new().fallback(RustlsCertificate::new()
+        .cert(files.cert.clone())
+        .key(files.key.clone()));
+
+    match &files.client_ca {
+        Some(ca) => config.client_auth_required(ca.clone()),
+        None => config
+    }
+}
+
+/// Generates a self-signed certificate if neither the certificate nor the key exist,
+/// then checks that all files can be read
+pub fn prepare(config: &TlsConfig) -> io::Result<()> {
+    if !Path::new(&config.cert).exists() && !Path::new(&config.key).exists() {
+        info!("No certificate found, generating a self-signed one at {}", config.cert);
+        generate_self_signed(config)?;
+    }
+
+    read_files(config).map(|_| ())
+}
+
+fn generate_self_signed(config: &TlsConfig) -> io::Result<()> {
+    let mut names = vec!["localhost".to_string()];
+    if let Some(hostname) = nix::unistd::gethostname().ok().and_then(|name| name.into_string().ok()) {
+        names.insert(0, hostname);
+    }
+
+    let cert = rcgen::generate_simple_self_signed(names).map_err(|e| io::Error::other(e.to_string()))?;
+    let cert_pem = cert.serialize_pem().map_err(|e| io::Error::other(e.to_string()))?;
+
+    fs::write(&config.cert, cert_pem)?;
+
+    let key = fs::File::create(&config.key)?;
+    if cfg!(target_os = "linux") {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mut perm = key.metadata()?.permissions();
+        perm.set_mode(0o600);
+        key.set_permissions(perm)?;
+    }
+    fs::write(&config.key, cert.serialize_private_key_pem())
+}
+
+/// Yields the tls config, and again every time one of the files changed
+pub fn watch(config: TlsConfig) -> impl Stream<Item = RustlsConfig> {
+    async_stream::stream! {
+        let mut loaded: Option<Files> = None;
+        loop {
+            match read_files(&config) {
+                Ok(files) => {
+                    if loaded.as_ref() != Some(&files) {
+                        if loaded.is_some() {
+                            info!("Certificate changed, reloading");
+                        }
+                        yield rustls_config(&files);
+                        loaded = Some(files);
+                    }
+                },
+                Err(e) => error!("Failed to read the certificate, keeping the current one: {}", e)
+            }
+
+            tokio::time::sleep(RELOAD_INTERVAL).await;
+        }
+    }
+}
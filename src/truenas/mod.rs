@@ -0,0 +1,385 @@
+pub mod websocket;
+
+use std::time::Duration;
+
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use url::Url;
+use uuid::Uuid;
+
+use crate::{data, Config};
+
+/// Name of the instance configured with `truenas_address` and `truenas_token`
+pub const DEFAULT_INSTANCE: &str = "truenas";
+
+/// How long an instance has to answer the ping before it counts as down
+const PING_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A TrueNAS server in `truenas_instances` of the config
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TruenasConfig {
+    /// Used to tag the alerts and pools, and to filter with `?instance=`
+    pub name: String,
+    pub address: Url,
+    pub token: String,
+    #[serde(default)]
+    pub accept_invalid_certs: bool
+}
+
+/// A TrueNAS server with the client used to reach it
+pub struct Instance {
+    pub name: String,
+    pub address: Url,
+    pub token: String,
+    pub accept_invalid_certs: bool,
+    client: Client
+}
+
+/// All configured TrueNAS servers, `truenas_address` as DEFAULT_INSTANCE (if use_truenas is set) followed by `truenas_instances`
+///
+/// A `truenas_address` without token is left out
+pub fn configured_instances(config: &Config) -> Vec<TruenasConfig> {
+    let mut list = Vec::new();
+
+    if config.use_truenas {
+        if let (Some(token), Some(address)) = (&config.truenas_token, &config.truenas_address) {
+            if !token.is_empty() && !address.cannot_be_a_base() {
+                list.push(TruenasConfig {
+                    name: DEFAULT_INSTANCE.to_string(),
+                    address: address.clone(),
+                    token: token.clone(),
+                    accept_invalid_certs: config.accept_invalid_certs
+                });
+            }
+        }
+    }
+
+    list.extend(config.truenas_instances.iter().cloned());
+    list
+}
+
+pub fn validate_instances(instances: &[TruenasConfig]) -> Result<(), String> {
+    for (index, instance) in instances.iter().enumerate() {
+        if instance.name.is_empty() {
+            return Err("TrueNAS instances need a name".to_string());
+        }
+        if instances[..index].iter().any(|item| item.name == instance.name) {
+            return Err(format!("The TrueNAS instance name {} is used more than once (the one set with truenas_address is called {})", instance.name, DEFAULT_INSTANCE));
+        }
+        if instance.token.is_empty() || instance.address.cannot_be_a_base() {
+            return Err(format!("TrueNAS instance {} needs an address and a token", instance.name));
+        }
+    }
+
+    Ok(())
+}
+
+pub fn new_instance(config: &TruenasConfig) -> Option<Instance> {
+    let client = reqwest::Client::builder()
+        .danger_accept_invalid_certs(config.accept_invalid_certs)
+        .build()
+        .ok()?;
+
+    Some(Instance {
+        name: config.name.clone(),
+        address: config.address.clone(),
+        token: config.token.clone(),
+        accept_invalid_certs: config.accept_invalid_certs,
+        client
+    })
+}
+
+pub async fn request(instance: &Instance, api_function: &str) -> Option<Vec<u8>> {
+    let target = instance.address.join("/api/v2.0/").ok()?.join(api_function).ok()?;
+    let req = instance.client.get(target).bearer_auth(&instance.token).build().ok()?;
+    let res = instance.client.execute(req).await.ok()?;
+    let result = res.bytes().await.ok()?;
+    Some(result.into())
+}
+
+pub async fn post(instance: &Instance, api_function: &str, body: &serde_json::Value) -> Option<Vec<u8>> {
+    let target = instance.address.join("/api/v2.0/").ok()?.join(api_function).ok()?;
+    let req = instance.client.post(target).bearer_auth(&instance.token).json(body).build().ok()?;
+    let res = instance.client.execute(req).await.ok()?.error_for_status().ok()?;
+    let result = res.bytes().await.ok()?;
+    Some(result.into())
+}
+
+pub async fn get_alerts(instance: &Instance) -> Option<Vec<data::Alert>> {
+    let internal: Vec<InternalAlert> = serde_json::from_slice(request(instance, "alert/list").await?.as_slice()).ok()?;
+
+    Some(internal.into_iter().map(|item| {
+        item.parse(&instance.name)
+    }).collect())
+}
+
+/// Pools with their topology, the disks of the devices are set, but not the serials, see `link_disks`
+pub async fn get_pools(instance: &Instance) -> Option<Vec<data::Pool>> {
+    // GET pool is pool/query
+    let internal: Vec<InternalPool> = serde_json::from_slice(request(instance, "pool").await?.as_slice()).ok()?;
+
+    Some(internal.into_iter().map(|item| item.parse(&instance.name)).collect())
+}
+
+pub async fn get_disks(instance: &Instance) -> Option<Vec<data::TruenasDisk>> {
+    // GET disk is disk/query
+    let internal: Vec<InternalDisk> = serde_json::from_slice(request(instance, "disk").await?.as_slice()).ok()?;
+
+    Some(internal.into_iter().map(|item| item.parse(&instance.name)).collect())
+}
+
+/// Sets the serials of the pool devices and the pools of the disks, both have to be from the same instance,
+/// and links both to the blockdevices of this machine with the same serial
+pub fn link_disks(pools: &mut [data::Pool], disks: &mut [data::TruenasDisk], host: &[data::Blockdevice]) {
+    let host_disk = |serial: &Option<String>| -> Option<String> {
+        let serial = serial.as_ref()?.trim();
+        host.iter()
+            .find(|device| device.serial.as_ref().is_some_and(|item| item.trim().eq_ignore_ascii_case(serial)))
+            .map(|device| device.name.clone())
+    };
+
+    for disk in disks.iter_mut() {
+        disk.host_disk = host_disk(&disk.serial);
+    }
+
+    for pool in pools.iter_mut() {
+        let mut stack: Vec<&mut data::Vdev> = pool.vdevs.iter_mut().collect();
+        while let Some(vdev) = stack.pop() {
+            if let Some(disk) = vdev.disk.as_ref().and_then(|name| disks.iter_mut().find(|disk| &disk.name == name)) {
+                disk.pool = Some(pool.name.clone());
+                vdev.serial = disk.serial.clone();
+                vdev.host_disk = disk.host_disk.clone();
+            }
+            stack.extend(vdev.children.iter_mut());
+        }
+    }
+}
+
+/// Dismisses the alert, or restores it if `dismiss` is false, returns true if TrueNAS accepted it
+pub async fn dismiss_alert(instance: &Instance, uuid: Uuid, dismiss: bool) -> bool {
+    let api_function = if dismiss { "alert/dismiss" } else { "alert/restore" };
+    post(instance, api_function, &serde_json::json!(uuid)).await.is_some()
+}
+
+pub async fn do_ping(instance: &Instance) -> Option<bool> {
+    let text: String = serde_json::from_slice(request(instance, "core/ping").await?.as_slice()).ok()?;
+    Some(text == "pong")
+}
+
+/// Pings all instances at once, so one that is down only delays the answer by PING_TIMEOUT
+pub async fn ping_all(instances: &[Instance]) -> Vec<bool> {
+    futures_util::future::join_all(instances.iter().map(|instance| async move {
+        tokio::time::timeout(PING_TIMEOUT, do_ping(instance)).await.ok().flatten().unwrap_or(false)
+    })).await
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct InternalAlert {
+    uuid: Uuid,
+    source: String,
+    klass: String,
+    node: String,
+    dismissed: bool,
+    #[serde(rename(deserialize = "formatted"))]
+    text: String,
+    level: String,
+    one_shot: bool,
+    datetime: Time,
+    last_occurrence: Time
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Time {
+    #[serde(rename(deserialize = "$date"))]
+    date: u64
+}
+
+impl InternalAlert {
+    fn parse(self, instance: &str) -> data::Alert {
+        data::Alert {
+            instance: instance.to_string(),
+            uuid: self.uuid,
+            source: self.source,
+            klass: self.klass,
+            node: self.node,
+            dismissed: self.dismissed,
+            text: self.text,
+            level: match self.level.as_str() {
+                "INFO" => data::AlertLevel::Info,
+                "WARNING" => data::AlertLevel::Warning,
+                "CRITICAL" => data::AlertLevel::Critical,
+                _ => data::AlertLevel::Unknown
+            },
+            one_shot: self.one_shot,
+            datetime_ms: self.datetime.date,
+            last_occurrence_ms: self.last_occurrence.date,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct InternalPool {
+    name: String,
+    status: String,
+    status_detail: Option<String>,
+    size: Option<u64>,
+    allocated: Option<u64>,
+    free: Option<u64>,
+    /// A string, like "12"
+    fragmentation: Option<serde_json::Value>,
+    scan: Option<InternalScan>,
+    topology: Option<InternalTopology>
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct InternalScan {
+    function: Option<String>,
+    state: Option<String>,
+    start_time: Option<Time>,
+    end_time: Option<Time>,
+    percentage: Option<f64>,
+    errors: Option<u64>
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct InternalTopology {
+    #[serde(default)]
+    data: Vec<InternalVdev>,
+    #[serde(default)]
+    log: Vec<InternalVdev>,
+    #[serde(default)]
+    cache: Vec<InternalVdev>,
+    #[serde(default)]
+    spare: Vec<InternalVdev>,
+    #[serde(default)]
+    special: Vec<InternalVdev>,
+    #[serde(default)]
+    dedup: Vec<InternalVdev>
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct InternalVdev {
+    name: String,
+    #[serde(rename = "type")]
+    vdev_type: String,
+    path: Option<String>,
+    status: String,
+    stats: Option<InternalVdevStats>,
+    disk: Option<String>,
+    #[serde(default)]
+    children: Vec<InternalVdev>
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct InternalVdevStats {
+    read_errors: u64,
+    write_errors: u64,
+    checksum_errors: u64
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct InternalDisk {
+    name: String,
+    serial: Option<String>,
+    model: Option<String>,
+    size: Option<u64>,
+    #[serde(rename = "type")]
+    disk_type: Option<String>
+}
+
+impl InternalPool {
+    fn parse(self, instance: &str) -> data::Pool {
+        let topology = self.topology.unwrap_or_default();
+
+        let mut vdevs = Vec::new();
+        for (class, items) in [("normal", topology.data), ("log", topology.log), ("cache", topology.cache), ("spare", topology.spare), ("special", topology.special), ("dedup", topology.dedup)] {
+            vdevs.extend(items.into_iter().map(|vdev| vdev.parse(class)));
+        }
+
+        // TrueNAS has no stats for the root vdev
+        let data = vdevs.iter().filter(|vdev| vdev.class == "normal");
+        let (read_errors, write_errors, checksum_errors) = data.fold((0, 0, 0), |sum, vdev| (sum.0 + vdev.read_errors, sum.1 + vdev.write_errors, sum.2 + vdev.checksum_errors));
+
+        let fragmentation_percent = match self.fragmentation {
+            Some(serde_json::Value::Number(number)) => number.as_u64(),
+            Some(serde_json::Value::String(text)) => text.trim_end_matches('%').parse().ok(),
+            _ => None
+        };
+
+        data::Pool {
+            instance: Some(instance.to_string()),
+            name: self.name,
+            state: self.status,
+            status: self.status_detail,
+            action: None,
+            capacity_percent: match (self.allocated, self.size) {
+                (Some(allocated), Some(size)) if size > 0 => Some(allocated * 100 / size),
+                _ => None
+            },
+            size_bytes: self.size,
+            allocated_bytes: self.allocated,
+            free_bytes: self.free,
+            fragmentation_percent,
+            read_errors,
+            write_errors,
+            checksum_errors,
+            scan: self.scan.and_then(|scan| scan.parse()),
+            vdevs,
+            errors: None
+        }
+    }
+}
+
+impl InternalScan {
+    fn parse(self) -> Option<data::PoolScan> {
+        let function = self.function?.to_lowercase();
+        let state = self.state.unwrap_or_default().to_lowercase();
+
+        Some(data::PoolScan {
+            function,
+            start_time_ms: self.start_time.map(|time| time.date),
+            end_time_ms: self.end_time.map(|time| time.date).filter(|_| state != "scanning"),
+            state,
+            progress_percent: self.percentage,
+            errors: self.errors,
+            description: None
+        })
+    }
+}
+
+impl InternalVdev {
+    fn parse(self, class: &str) -> data::Vdev {
+        let stats = self.stats.unwrap_or_default();
+
+        data::Vdev {
+            name: self.name,
+            vdev_type: Some(self.vdev_type.to_lowercase()),
+            class: class.to_string(),
+            state: self.status,
+            read_errors: stats.read_errors,
+            write_errors: stats.write_errors,
+            checksum_errors: stats.checksum_errors,
+            path: self.path,
+            disk: self.disk,
+            serial: None,
+            host_disk: None,
+            children: self.children.into_iter().map(|vdev| vdev.parse(class)).collect()
+        }
+    }
+}
+
+impl InternalDisk {
+    fn parse(self, instance: &str) -> data::TruenasDisk {
+        data::TruenasDisk {
+            instance: instance.to_string(),
+            name: self.name,
+            // Virtual disks without a serial report an empty one
+            serial: self.serial.filter(|serial| !serial.trim().is_empty()),
+            model: self.model,
+            size_bytes: self.size,
+            disk_type: self.disk_type,
+            pool: None,
+            host_disk: None
+        }
+    }
+}
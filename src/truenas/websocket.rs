@@ -0,0 +1,520 @@
+use std::{collections::HashMap, time::Duration};
+
+use futures_util::{SinkExt, StreamExt};
+use log::debug;
+use serde_json::{json, Value};
+use tokio::{net::TcpStream, time::Instant};
+use tokio_tungstenite::{tungstenite::{self, Message}, Connector, MaybeTlsStream, WebSocketStream};
+use uuid::Uuid;
+
+use crate::data;
+
+use super::{Instance, InternalAlert};
+
+// Receives the TrueNAS alerts over the websocket api as soon as they change, instead of polling them
+// Newer releases speak JSON-RPC 2.0 on /api/current, older ones the DDP like protocol on /websocket
+
+/// Without any message for this long the connection is pinged, and closed if there is still no answer
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+const ALERT_COLLECTION: &str = "alert.list";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Protocol {
+    JsonRpc,
+    Legacy
+}
+
+pub struct Connection {
+    socket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    protocol: Protocol,
+    next_id: u64,
+    /// Name of the instance, to tag the alerts with
+    instance: String,
+    alerts: HashMap<Uuid, data::Alert>,
+    /// Kept here instead of in `receive`, so waiting for the next message can be canceled
+    last_received: Instant,
+    pinged: bool
+}
+
+/// What a message did to the alerts
+enum Change {
+    None,
+    Updated,
+    /// The change only contained some of the fields, so the whole list has to be read again
+    Incomplete
+}
+
+/// Something the server sent us
+enum Incoming {
+    /// Answer to a method call
+    Result(String, Result<Value, String>),
+    /// A change of the alert list, added, changed or removed
+    Event(String, Value, Option<Value>),
+    Ping(Option<Value>),
+    Other
+}
+
+/// Connects to the websocket of TrueNAS, logs in with the api key and subscribes to the alerts
+///
+/// The address is the same as for the rest api, http becomes ws and https wss
+pub async fn connect(instance: &Instance) -> Result<Connection, String> {
+    let address = &instance.address;
+    let mut last_error = String::new();
+
+    for (path, protocol) in [("/api/current", Protocol::JsonRpc), ("/websocket", Protocol::Legacy)] {
+        let mut url = address.join(path).map_err(|e| e.to_string())?;
+        let scheme = if url.scheme() == "https" { "wss" } else { "ws" };
+        url.set_scheme(scheme).map_err(|_| format!("Can not use {} for the websocket", address))?;
+
+        let connector = if scheme == "wss" {
+            let tls = native_tls::TlsConnector::builder()
+                .danger_accept_invalid_certs(instance.accept_invalid_certs)
+                .build()
+                .map_err(|e| e.to_string())?;
+            Some(Connector::NativeTls(tls))
+        } else {
+            None
+        };
+
+        match tokio_tungstenite::connect_async_tls_with_config(url.as_str(), None, false, connector).await {
+            Ok((socket, _)) => {
+                debug!("Connected to {}", url);
+                let mut connection = Connection {
+                    socket, protocol, next_id: 0, instance: instance.name.clone(), alerts: HashMap::new(),
+                    last_received: Instant::now(), pinged: false
+                };
+                connection.setup(&instance.token).await?;
+                return Ok(connection);
+            },
+            // Older releases do not have /api/current, and newer ones may drop /websocket
+            Err(tungstenite::Error::Http(response)) => last_error = format!("{} returned {}", url, response.status()),
+            Err(e) => return Err(format!("Failed to connect to {}: {}", url, e))
+        }
+    }
+
+    Err(last_error)
+}
+
+impl Connection {
+    async fn setup(&mut self, token: &str) -> Result<(), String> {
+        if self.protocol == Protocol::Legacy {
+            self.send(json!({ "msg": "connect", "version": "1", "support": ["1"] })).await?;
+            loop {
+                let message = self.receive().await?;
+                match message.get("msg").and_then(Value::as_str) {
+                    Some("connected") => break,
+                    Some("failed") => return Err("The server refused the connection".to_string()),
+                    _ => ()
+                }
+            }
+        }
+
+        match self.call("auth.login_with_api_key", json!([token])).await? {
+            Value::Bool(true) => (),
+            _ => return Err("Login with the api key failed".to_string())
+        }
+
+        match self.protocol {
+            Protocol::JsonRpc => {
+                self.call("core.subscribe", json!([ALERT_COLLECTION])).await?;
+            },
+            Protocol::Legacy => {
+                let id = Uuid::new_v4().to_string();
+                self.send(json!({ "msg": "sub", "id": id, "name": ALERT_COLLECTION })).await?;
+            }
+        }
+
+        self.reload_alerts().await
+    }
+
+    /// All current alerts, oldest first
+    pub fn alerts(&self) -> Vec<data::Alert> {
+        let mut list: Vec<data::Alert> = self.alerts.values().cloned().collect();
+        list.sort_by_key(|alert| (alert.datetime_ms, alert.uuid));
+        list
+    }
+
+    /// Waits until the alerts change and returns all current alerts, an error means the connection is lost
+    pub async fn next(&mut self) -> Result<Vec<data::Alert>, String> {
+        loop {
+            let message = self.receive().await?;
+            match self.handle(message).await? {
+                Change::None => (),
+                Change::Updated => return Ok(self.alerts()),
+                Change::Incomplete => {
+                    self.reload_alerts().await?;
+                    return Ok(self.alerts());
+                }
+            }
+        }
+    }
+
+    async fn reload_alerts(&mut self) -> Result<(), String> {
+        let list: Vec<InternalAlert> = serde_json::from_value(self.call("alert.list", json!([])).await?)
+            .map_err(|e| format!("Failed to parse the alerts: {}", e))?;
+
+        self.alerts = list.into_iter().map(|item| {
+            let alert = item.parse(&self.instance);
+            (alert.uuid, alert)
+        }).collect();
+
+        Ok(())
+    }
+
+    /// Applies a message that was not asked for
+    async fn handle(&mut self, message: Value) -> Result<Change, String> {
+        match self.parse(message) {
+            Incoming::Event(kind, id, fields) => {
+                let alert = fields.and_then(|fields| serde_json::from_value::<InternalAlert>(fields).ok()).map(|item| item.parse(&self.instance));
+
+                match (kind.as_str(), alert) {
+                    ("added" | "changed", Some(alert)) => {
+                        self.alerts.insert(alert.uuid, alert);
+                    },
+                    ("removed", _) => {
+                        if let Some(uuid) = id.as_str().and_then(|id| Uuid::parse_str(id).ok()) {
+                            self.alerts.remove(&uuid);
+                        }
+                    },
+                    _ => return Ok(Change::Incomplete)
+                }
+
+                Ok(Change::Updated)
+            },
+            Incoming::Ping(id) => {
+                let mut pong = json!({ "msg": "pong" });
+                if let Some(id) = id {
+                    pong["id"] = id;
+                }
+                self.send(pong).await?;
+                Ok(Change::None)
+            },
+            _ => Ok(Change::None)
+        }
+    }
+
+    /// Calls a method and waits for its result, events arriving in the meantime are applied
+    ///
+    /// Incomplete changes can be skipped here, as every call is either alert.list itself,
+    /// or part of the setup, which reads the alert list afterwards
+    async fn call(&mut self, method: &str, params: Value) -> Result<Value, String> {
+        self.next_id += 1;
+        let id = self.next_id.to_string();
+
+        let request = match self.protocol {
+            Protocol::JsonRpc => json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params }),
+            Protocol::Legacy => json!({ "msg": "method", "id": id, "method": method, "params": params })
+        };
+        self.send(request).await?;
+
+        loop {
+            let message = self.receive().await?;
+            match self.parse(message.clone()) {
+                Incoming::Result(result_id, result) if result_id == id => {
+                    return result.map_err(|e| format!("{} failed: {}", method, e));
+                },
+                _ => {
+                    self.handle(message).await?;
+                }
+            }
+        }
+    }
+
+    fn parse(&self, message: Value) -> Incoming {
+        let id = message.get("id").map(|id| match id {
+            Value::String(id) => id.clone(),
+            id => id.to_string()
+        });
+
+        match self.protocol {
+            Protocol::JsonRpc => {
+                if let (Some(id), None) = (&id, message.get("method")) {
+                    return Incoming::Result(id.clone(), match message.get("error") {
+                        Some(error) => Err(error.get("message").and_then(Value::as_str).map(str::to_string).unwrap_or(error.to_string())),
+                        None => Ok(message.get("result").cloned().unwrap_or(Value::Null))
+                    });
+                }
+
+                match (message.get("method").and_then(Value::as_str), message.get("params")) {
+                    (Some("collection_update"), Some(params)) => event(params),
+                    _ => Incoming::Other
+                }
+            },
+            Protocol::Legacy => match message.get("msg").and_then(Value::as_str) {
+                Some("result") => Incoming::Result(id.unwrap_or_default(), match message.get("error") {
+                    Some(error) => Err(error.get("reason").or(error.get("error")).and_then(Value::as_str).map(str::to_string).unwrap_or(error.to_string())),
+                    None => Ok(message.get("result").cloned().unwrap_or(Value::Null))
+                }),
+                Some("added" | "changed" | "removed") => event(&message),
+                Some("ping") => Incoming::Ping(message.get("id").cloned()),
+                _ => Incoming::Other
+            }
+        }
+    }
+
+    async fn send(&mut self, message: Value) -> Result<(), String> {
+        self.socket.send(Message::Text(message.to_string())).await.map_err(|e| format!("Failed to send: {}", e))
+    }
+
+    /// Waits for the next json message, pinging the server if it is quiet for too long
+    async fn receive(&mut self) -> Result<Value, String> {
+        loop {
+            let deadline = self.last_received + if self.pinged { TIMEOUT * 2 } else { TIMEOUT };
+            let message = match tokio::time::timeout_at(deadline, self.socket.next()).await {
+                Ok(Some(Ok(message))) => message,
+                Ok(Some(Err(e))) => return Err(e.to_string()),
+                Ok(None) => return Err("Connection closed".to_string()),
+                Err(_) if self.pinged => return Err("Connection timed out".to_string()),
+                Err(_) => {
+                    self.pinged = true;
+                    self.socket.send(Message::Ping(Vec::new())).await.map_err(|e| format!("Failed to send: {}", e))?;
+                    continue;
+                }
+            };
+            self.last_received = Instant::now();
+            self.pinged = false;
+
+            match message {
+                Message::Text(text) => {
+                    if let Ok(value) = serde_json::from_str(&text) {
+                        return Ok(value);
+                    }
+                    debug!("Ignoring invalid message from TrueNAS: {}", text);
+                },
+                Message::Close(_) => return Err("Connection closed by TrueNAS".to_string()),
+                _ => ()
+            }
+        }
+    }
+}
+
+/// A collection update, which has the same fields in both protocols
+fn event(message: &Value) -> Incoming {
+    if message.get("collection").and_then(Value::as_str) != Some(ALERT_COLLECTION) {
+        return Incoming::Other;
+    }
+
+    Incoming::Event(
+        message.get("msg").and_then(Value::as_str).unwrap_or_default().to_string(),
+        message.get("id").cloned().unwrap_or(Value::Null),
+        message.get("fields").cloned()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, sync::{atomic::{AtomicUsize, Ordering}, Arc, Mutex}};
+
+    use futures_util::{SinkExt, StreamExt};
+    use serde_json::{json, Value};
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::tungstenite::{handshake::server::{ErrorResponse, Request, Response}, http::StatusCode, Message};
+    use url::Url;
+    use uuid::Uuid;
+
+    use super::connect;
+    use crate::truenas::{new_instance, Instance, TruenasConfig};
+
+    const FIRST: &str = "11111111-1111-1111-1111-111111111111";
+    const SECOND: &str = "22222222-2222-2222-2222-222222222222";
+    const TOKEN: &str = "secret";
+
+    fn alert(uuid: &str, text: &str, dismissed: bool) -> Value {
+        json!({
+            "uuid": uuid, "source": "VolumeStatus", "klass": "VolumeStatus", "node": "A", "dismissed": dismissed,
+            "formatted": text, "level": "CRITICAL", "one_shot": false, "datetime": { "$date": 1 }, "last_occurrence": { "$date": 2 }
+        })
+    }
+
+    /// State of the mock TrueNAS, shared by all connections
+    #[derive(Default)]
+    struct Mock {
+        alerts: Mutex<Vec<Value>>,
+        /// Every message the clients sent
+        received: Mutex<Vec<Value>>,
+        connections: AtomicUsize
+    }
+
+    /// Serves the alert websocket, the first connection gets an alert added, one removed and one changed
+    /// (with only some of the fields, so the list has to be read again), and is then closed
+    ///
+    /// With legacy_only /api/current is refused, like on older releases
+    async fn mock_server(legacy_only: bool) -> (SocketAddr, Arc<Mock>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let mock = Arc::new(Mock::default());
+        mock.alerts.lock().unwrap().push(alert(FIRST, "Pool tank is DEGRADED", false));
+
+        let state = mock.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mock = state.clone();
+                tokio::spawn(async move {
+                    let mut path = String::new();
+                    // The error type is given by tungstenite
+                    #[allow(clippy::result_large_err)]
+                    let callback = |request: &Request, response: Response| -> Result<Response, ErrorResponse> {
+                        path = request.uri().path().to_string();
+                        if legacy_only && path == "/api/current" {
+                            let mut error = ErrorResponse::new(None);
+                            *error.status_mut() = StatusCode::NOT_FOUND;
+                            return Err(error);
+                        }
+                        Ok(response)
+                    };
+                    let Ok(socket) = tokio_tungstenite::accept_hdr_async(stream, callback).await else {
+                        return;
+                    };
+                    serve(socket, path == "/api/current", mock).await;
+                });
+            }
+        });
+
+        (address, mock)
+    }
+
+    async fn serve(mut socket: tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>, rpc: bool, mock: Arc<Mock>) {
+        let first_connection = mock.connections.fetch_add(1, Ordering::SeqCst) == 0;
+        let wrap = |event: Value| if rpc { json!({ "jsonrpc": "2.0", "method": "collection_update", "params": event }) } else { event };
+        let mut lists = 0;
+
+        while let Some(Ok(message)) = socket.next().await {
+            let Message::Text(text) = message else {
+                continue;
+            };
+            let message: Value = serde_json::from_str(&text).unwrap();
+            mock.received.lock().unwrap().push(message.clone());
+
+            match message["msg"].as_str() {
+                Some("connect") => {
+                    socket.send(Message::Text(json!({ "msg": "connected", "session": "s" }).to_string())).await.unwrap();
+                    continue;
+                },
+                Some("sub" | "pong") => continue,
+                _ => ()
+            }
+
+            let method = message["method"].as_str().unwrap_or_default();
+            let result = match method {
+                "auth.login_with_api_key" => json!(message["params"][0] == TOKEN),
+                "core.subscribe" => json!("subscription"),
+                "alert.list" => json!(*mock.alerts.lock().unwrap()),
+                _ => Value::Null
+            };
+            let reply = if rpc {
+                json!({ "jsonrpc": "2.0", "id": message["id"], "result": result })
+            } else {
+                json!({ "msg": "result", "id": message["id"], "result": result })
+            };
+            socket.send(Message::Text(reply.to_string())).await.unwrap();
+
+            if method != "alert.list" || !first_connection {
+                continue;
+            }
+            lists += 1;
+
+            if lists == 1 {
+                if !rpc {
+                    socket.send(Message::Text(json!({ "msg": "ping", "id": "p1" }).to_string())).await.unwrap();
+                }
+
+                let events = {
+                    let mut alerts = mock.alerts.lock().unwrap();
+                    alerts.push(alert(SECOND, "Disk sdc is FAULTED", false));
+                    alerts.remove(0);
+                    alerts[0]["dismissed"] = json!(true);
+
+                    vec![
+                        json!({ "msg": "added", "collection": "alert.list", "id": SECOND, "fields": alert(SECOND, "Disk sdc is FAULTED", false) }),
+                        json!({ "msg": "removed", "collection": "alert.list", "id": FIRST }),
+                        json!({ "msg": "changed", "collection": "alert.list", "id": SECOND, "fields": { "dismissed": true } })
+                    ]
+                };
+                for event in events {
+                    socket.send(Message::Text(wrap(event).to_string())).await.unwrap();
+                }
+            } else {
+                // The list was read again after the incomplete change, now the connection drops
+                let _ = socket.close(None).await;
+                return;
+            }
+        }
+    }
+
+    fn instance(address: SocketAddr, token: &str) -> Instance {
+        new_instance(&TruenasConfig {
+            name: "vm1".to_string(),
+            address: Url::parse(&format!("http://{}/", address)).unwrap(),
+            token: token.to_string(),
+            accept_invalid_certs: false
+        }).unwrap()
+    }
+
+    fn uuids(alerts: &[crate::data::Alert]) -> Vec<String> {
+        alerts.iter().map(|alert| alert.uuid.to_string()).collect()
+    }
+
+    async fn receives_changes_and_reconnects(legacy_only: bool) {
+        let (address, mock) = mock_server(legacy_only).await;
+        let instance = instance(address, TOKEN);
+
+        let mut connection = connect(&instance).await.expect("should connect and log in");
+        let alerts = connection.alerts();
+        assert_eq!(uuids(&alerts), vec![FIRST]);
+        assert_eq!(alerts[0].instance, "vm1");
+
+        let added = connection.next().await.expect("added event");
+        assert_eq!(uuids(&added), vec![FIRST, SECOND]);
+
+        let removed = connection.next().await.expect("removed event");
+        assert_eq!(uuids(&removed), vec![SECOND]);
+
+        let changed = connection.next().await.expect("changed event");
+        assert_eq!(uuids(&changed), vec![SECOND]);
+        assert!(changed[0].dismissed, "the incomplete change should read the list again");
+
+        assert!(connection.next().await.is_err(), "the closed connection should be reported");
+
+        let connection = connect(&instance).await.expect("should reconnect");
+        assert_eq!(uuids(&connection.alerts()), vec![SECOND]);
+
+        let received = mock.received.lock().unwrap();
+        let methods: Vec<&str> = received.iter().filter_map(|message| message["method"].as_str()).collect();
+        assert_eq!(methods.iter().filter(|method| **method == "auth.login_with_api_key").count(), 2);
+        if legacy_only {
+            assert!(received.iter().any(|message| message["msg"] == "sub" && message["name"] == "alert.list"));
+            assert!(received.iter().any(|message| message["msg"] == "pong" && message["id"] == "p1"));
+        } else {
+            assert!(received.iter().any(|message| message["method"] == "core.subscribe" && message["params"][0] == "alert.list"));
+        }
+    }
+
+    #[tokio::test]
+    async fn json_rpc_receives_changes_and_reconnects() {
+        receives_changes_and_reconnects(false).await;
+    }
+
+    #[tokio::test]
+    async fn legacy_receives_changes_and_reconnects() {
+        receives_changes_and_reconnects(true).await;
+    }
+
+    #[tokio::test]
+    async fn refuses_wrong_token() {
+        let (address, _) = mock_server(false).await;
+
+        let res = connect(&instance(address, "wrong")).await;
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn ignores_other_collections() {
+        let event = super::event(&json!({ "msg": "added", "collection": "pool.query", "id": 1, "fields": {} }));
+        assert!(matches!(event, super::Incoming::Other));
+
+        let event = super::event(&json!({ "msg": "removed", "collection": "alert.list", "id": Uuid::nil().to_string() }));
+        assert!(matches!(event, super::Incoming::Event(kind, _, None) if kind == "removed"));
+    }
+}
@@ -0,0 +1,549 @@
+use std::{collections::BTreeMap, process::Command};
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::data::{Pool, PoolScan, Vdev};
+
+// Reads the status of the local ZFS pools via zpool
+// OpenZFS 2.3 and newer can output json, for older versions the text output is parsed
+
+/// Headers of the vdev classes in the config section of the text output, with the name of the class in the json output
+const CLASSES: [(&str, &str); 5] = [("logs", "log"), ("cache", "cache"), ("spares", "spare"), ("special", "special"), ("dedup", "dedup")];
+
+/// Is true if the zpool command is available
+pub fn is_available() -> bool {
+    if cfg!(target_os = "windows") {
+        return false;
+    }
+
+    Command::new("zpool").arg("version").output().map(|output| output.status.success()).unwrap_or(false)
+}
+
+pub fn get_pools() -> Option<Vec<Pool>> {
+    if cfg!(target_os = "windows") {
+        return None;
+    }
+
+    if let Some(pools) = get_pools_json() {
+        return Some(pools);
+    }
+
+    get_pools_text()
+}
+
+#[derive(Deserialize)]
+struct JsonOutput<T> {
+    pools: BTreeMap<String, T>
+}
+
+#[derive(Deserialize)]
+struct JsonPool {
+    name: String,
+    state: String,
+    status: Option<String>,
+    action: Option<String>,
+    scan_stats: Option<JsonScan>,
+    #[serde(default)]
+    vdevs: BTreeMap<String, JsonVdev>,
+    #[serde(default)]
+    logs: BTreeMap<String, JsonVdev>,
+    #[serde(default)]
+    l2cache: BTreeMap<String, JsonVdev>,
+    #[serde(default)]
+    spares: BTreeMap<String, JsonVdev>,
+    #[serde(default)]
+    special: BTreeMap<String, JsonVdev>,
+    #[serde(default)]
+    dedup: BTreeMap<String, JsonVdev>,
+    error_count: Option<Value>
+}
+
+#[derive(Deserialize)]
+struct JsonVdev {
+    name: String,
+    vdev_type: Option<String>,
+    class: Option<String>,
+    state: String,
+    path: Option<String>,
+    read_errors: Option<Value>,
+    write_errors: Option<Value>,
+    checksum_errors: Option<Value>,
+    #[serde(default)]
+    vdevs: BTreeMap<String, JsonVdev>
+}
+
+#[derive(Deserialize)]
+struct JsonScan {
+    function: String,
+    state: String,
+    start_time: Option<Value>,
+    end_time: Option<Value>,
+    to_examine: Option<Value>,
+    examined: Option<Value>,
+    issued: Option<Value>,
+    errors: Option<Value>
+}
+
+#[derive(Deserialize)]
+struct JsonListPool {
+    properties: BTreeMap<String, JsonProperty>
+}
+
+#[derive(Deserialize)]
+struct JsonProperty {
+    value: Value
+}
+
+/// Numbers are strings unless --json-int is supported, percentages may have a %, and unset values are "-"
+fn json_number(value: Option<&Value>) -> Option<u64> {
+    match value? {
+        Value::Number(number) => number.as_u64(),
+        Value::String(text) => text.trim_end_matches('%').parse().ok(),
+        _ => None
+    }
+}
+
+fn get_pools_json() -> Option<Vec<Pool>> {
+    let output = Command::new("zpool").args(["status", "-j", "--json-int"]).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let status: JsonOutput<JsonPool> = serde_json::from_slice(output.stdout.as_slice()).ok()?;
+
+    let output = Command::new("zpool").args(["list", "-j", "--json-int", "-o", "name,size,allocated,free,fragmentation,capacity"]).output().ok()?;
+    let list: JsonOutput<JsonListPool> = serde_json::from_slice(output.stdout.as_slice()).unwrap_or(JsonOutput { pools: BTreeMap::new() });
+
+    Some(status.pools.into_values().map(|pool| {
+        let properties = list.pools.get(&pool.name).map(|item| &item.properties);
+        let property = |name: &str| json_number(properties.and_then(|properties| properties.get(name)).map(|property| &property.value));
+
+        // The root vdev carries the errors of the pool itself
+        let root = pool.vdevs.into_values().next();
+        let mut vdevs: Vec<Vdev> = Vec::new();
+        if let Some(root) = &root {
+            vdevs.extend(root.vdevs.values().map(|vdev| vdev.parse("normal")));
+        }
+        for (class, items) in [("log", &pool.logs), ("cache", &pool.l2cache), ("spare", &pool.spares), ("special", &pool.special), ("dedup", &pool.dedup)] {
+            vdevs.extend(items.values().map(|vdev| vdev.parse(class)));
+        }
+
+        let errors = match json_number(pool.error_count.as_ref()) {
+            Some(0) => Some("No known data errors".to_string()),
+            Some(count) => Some(format!("{} data errors", count)),
+            None => None
+        };
+
+        Pool {
+            instance: None,
+            name: pool.name,
+            state: pool.state,
+            status: pool.status,
+            action: pool.action,
+            size_bytes: property("size"),
+            allocated_bytes: property("allocated"),
+            free_bytes: property("free"),
+            fragmentation_percent: property("fragmentation"),
+            capacity_percent: property("capacity"),
+            read_errors: root.as_ref().and_then(|root| json_number(root.read_errors.as_ref())).unwrap_or(0),
+            write_errors: root.as_ref().and_then(|root| json_number(root.write_errors.as_ref())).unwrap_or(0),
+            checksum_errors: root.as_ref().and_then(|root| json_number(root.checksum_errors.as_ref())).unwrap_or(0),
+            scan: pool.scan_stats.and_then(|scan| scan.parse()),
+            vdevs,
+            errors
+        }
+    }).collect())
+}
+
+impl JsonVdev {
+    fn parse(&self, class: &str) -> Vdev {
+        Vdev {
+            name: self.name.clone(),
+            vdev_type: self.vdev_type.clone(),
+            class: self.class.clone().unwrap_or(class.to_string()),
+            state: self.state.clone(),
+            read_errors: json_number(self.read_errors.as_ref()).unwrap_or(0),
+            write_errors: json_number(self.write_errors.as_ref()).unwrap_or(0),
+            checksum_errors: json_number(self.checksum_errors.as_ref()).unwrap_or(0),
+            path: self.path.clone(),
+            disk: None,
+            serial: None,
+            host_disk: None,
+            children: self.vdevs.values().map(|vdev| vdev.parse(class)).collect()
+        }
+    }
+}
+
+impl JsonScan {
+    fn parse(self) -> Option<PoolScan> {
+        let function = self.function.to_lowercase();
+        if function == "none" {
+            return None;
+        }
+
+        let state = self.state.to_lowercase();
+        let to_examine = json_number(self.to_examine.as_ref());
+        let done = json_number(self.issued.as_ref()).or(json_number(self.examined.as_ref()));
+        let progress_percent = match (state.as_str(), done, to_examine) {
+            ("finished", _, _) => Some(100.0),
+            (_, Some(done), Some(total)) if total > 0 => Some((done as f64 / total as f64 * 100.0).min(100.0)),
+            _ => None
+        };
+
+        // Only with --json-int the times are unix timestamps, otherwise they are formated dates
+        Some(PoolScan {
+            function,
+            start_time_ms: json_number(self.start_time.as_ref()).filter(|time| *time > 0).map(|time| time * 1000),
+            end_time_ms: json_number(self.end_time.as_ref()).filter(|time| *time > 0 && state != "scanning").map(|time| time * 1000),
+            state,
+            progress_percent,
+            errors: json_number(self.errors.as_ref()),
+            description: None
+        })
+    }
+}
+
+fn get_pools_text() -> Option<Vec<Pool>> {
+    let output = Command::new("zpool").args(["status", "-p"]).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let mut pools = parse_status(&String::from_utf8_lossy(&output.stdout))?;
+
+    let output = Command::new("zpool").args(["list", "-Hp", "-o", "name,size,allocated,free,fragmentation,capacity"]).output().ok()?;
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        let columns: Vec<&str> = line.split('\t').collect();
+        if columns.len() < 6 {
+            continue;
+        }
+
+        if let Some(pool) = pools.iter_mut().find(|pool| pool.name == columns[0]) {
+            let number = |text: &str| text.trim_end_matches('%').parse::<u64>().ok();
+            pool.size_bytes = number(columns[1]);
+            pool.allocated_bytes = number(columns[2]);
+            pool.free_bytes = number(columns[3]);
+            pool.fragmentation_percent = number(columns[4]);
+            pool.capacity_percent = number(columns[5]);
+        }
+    }
+
+    Some(pools)
+}
+
+/// Parses the output of `zpool status`, fields can span multiple lines, the following lines are indented with a tab
+fn parse_status(output: &str) -> Option<Vec<Pool>> {
+    let mut pools = Vec::<Pool>::new();
+    let mut field = String::new();
+    let mut scan_lines = Vec::<String>::new();
+    let mut tree = VdevTree::default();
+
+    for line in output.lines() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            continue;
+        }
+
+        if let Some((key, value)) = trimmed.split_once(':').filter(|(key, _)| !line.starts_with('\t') && !key.contains(' ')) {
+            let value = value.trim().to_string();
+            field = key.to_string();
+
+            match key {
+                "pool" => {
+                    if let Some(pool) = pools.last_mut() {
+                        finish_pool(pool, &mut tree, &mut scan_lines);
+                    }
+                    pools.push(new_pool(value));
+                },
+                "state" => pools.last_mut()?.state = value,
+                "status" => pools.last_mut()?.status = Some(value),
+                "action" => pools.last_mut()?.action = Some(value),
+                "scan" => scan_lines.push(value),
+                "errors" => pools.last_mut()?.errors = Some(value),
+                _ => ()
+            }
+            continue;
+        }
+
+        let Some(pool) = pools.last_mut() else {
+            continue;
+        };
+        match field.as_str() {
+            "status" => append(&mut pool.status, trimmed),
+            "action" => append(&mut pool.action, trimmed),
+            "scan" => scan_lines.push(trimmed.to_string()),
+            "errors" => append(&mut pool.errors, trimmed),
+            "config" => tree.add_line(pool, line),
+            _ => ()
+        }
+    }
+
+    if let Some(pool) = pools.last_mut() {
+        finish_pool(pool, &mut tree, &mut scan_lines);
+    }
+
+    Some(pools)
+}
+
+fn new_pool(name: String) -> Pool {
+    Pool {
+        instance: None, name, state: String::new(), status: None, action: None, size_bytes: None, allocated_bytes: None, free_bytes: None,
+        fragmentation_percent: None, capacity_percent: None, read_errors: 0, write_errors: 0, checksum_errors: 0,
+        scan: None, vdevs: Vec::new(), errors: None
+    }
+}
+
+fn append(field: &mut Option<String>, line: &str) {
+    if let Some(text) = field.as_mut() {
+        text.push(' ');
+        text.push_str(line);
+    }
+}
+
+fn finish_pool(pool: &mut Pool, tree: &mut VdevTree, scan_lines: &mut Vec<String>) {
+    pool.vdevs = std::mem::take(tree).finish();
+    pool.scan = parse_scan(scan_lines);
+    scan_lines.clear();
+}
+
+/// Parses the scan field, like "scrub repaired 0B in 00:10:21 with 0 errors on Sun Oct 13 00:34:22 2024"
+/// or "resilver in progress since ..." followed by the progress on the next lines
+fn parse_scan(lines: &[String]) -> Option<PoolScan> {
+    let first = lines.first()?;
+    let function = if first.starts_with("scrub") {
+        "scrub"
+    } else if first.starts_with("resilver") {
+        "resilver"
+    } else {
+        // "none requested"
+        return None;
+    };
+
+    let state = if first.contains("in progress") || first.contains("paused") {
+        "scanning"
+    } else if first.contains("canceled") {
+        "canceled"
+    } else {
+        "finished"
+    };
+
+    let words: Vec<&str> = lines.iter().flat_map(|line| line.split_whitespace()).collect();
+    let progress_percent = if state == "finished" {
+        Some(100.0)
+    } else {
+        words.windows(2)
+            .find(|pair| pair[1].trim_end_matches(',') == "done")
+            .and_then(|pair| pair[0].trim_end_matches('%').parse::<f64>().ok())
+    };
+    let errors = words.windows(2)
+        .find(|pair| pair[1] == "errors")
+        .and_then(|pair| pair[0].parse::<u64>().ok());
+
+    Some(PoolScan {
+        function: function.to_string(),
+        state: state.to_string(),
+        start_time_ms: None,
+        end_time_ms: None,
+        progress_percent,
+        errors,
+        description: Some(lines.join(" "))
+    })
+}
+
+/// Builds the vdevs from the config section, the depth is given by the indentation (2 spaces per level)
+#[derive(Default)]
+struct VdevTree {
+    /// Finished top level vdevs
+    vdevs: Vec<Vdev>,
+    /// The current branch, index 0 is a top level vdev
+    stack: Vec<(usize, Vdev)>,
+    class: String
+}
+
+impl VdevTree {
+    fn add_line(&mut self, pool: &mut Pool, line: &str) {
+        let Some(line) = line.strip_prefix('\t') else {
+            return;
+        };
+        let depth = (line.len() - line.trim_start().len()) / 2;
+        let columns: Vec<&str> = line.split_whitespace().collect();
+
+        if columns.len() >= 3 && columns[1] == "STATE" && columns[2] == "READ" {
+            return;
+        }
+
+        if depth == 0 {
+            self.collapse(0);
+            if columns.len() == 1 {
+                if let Some((_, class)) = CLASSES.iter().find(|(header, _)| *header == columns[0]) {
+                    self.class = class.to_string();
+                }
+            } else {
+                // The root vdev, which has the name of the pool
+                self.class = "normal".to_string();
+                let (read, write, checksum) = error_columns(&columns);
+                pool.read_errors = read;
+                pool.write_errors = write;
+                pool.checksum_errors = checksum;
+            }
+            return;
+        }
+
+        let (read_errors, write_errors, checksum_errors) = error_columns(&columns);
+        let vdev = Vdev {
+            name: columns[0].to_string(),
+            vdev_type: None,
+            class: if self.class.is_empty() { "normal".to_string() } else { self.class.clone() },
+            state: columns.get(1).unwrap_or(&"").to_string(),
+            read_errors,
+            write_errors,
+            checksum_errors,
+            path: None,
+            disk: None,
+            serial: None,
+            host_disk: None,
+            children: Vec::new()
+        };
+
+        self.collapse(depth);
+        self.stack.push((depth, vdev));
+    }
+
+    /// Attaches all vdevs on the stack with a depth of at least `depth` to their parent
+    fn collapse(&mut self, depth: usize) {
+        while self.stack.last().is_some_and(|(level, _)| *level >= depth) {
+            let (_, vdev) = self.stack.pop().expect("stack is not empty");
+            match self.stack.last_mut() {
+                Some((_, parent)) => parent.children.push(vdev),
+                None => self.vdevs.push(vdev)
+            }
+        }
+    }
+
+    fn finish(mut self) -> Vec<Vdev> {
+        self.collapse(0);
+        self.vdevs
+    }
+}
+
+/// Spares only have a state (AVAIL or INUSE), so the errors are 0
+fn error_columns(columns: &[&str]) -> (u64, u64, u64) {
+    let number = |index: usize| columns.get(index).and_then(|value| value.parse::<u64>().ok()).unwrap_or(0);
+    (number(2), number(3), number(4))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_scan, parse_status};
+
+    /// `zpool status -p` of a mirror pool resilvering onto a hot spare, with a log mirror and a cache device
+    const RESILVER: &str = "  pool: tank
+ state: DEGRADED
+status: One or more devices is currently being resilvered.  The pool will
+\tcontinue to function, possibly in a degraded state.
+action: Wait for the resilver to complete.
+  scan: resilver in progress since Sat Oct 12 10:00:00 2024
+\t812G scanned at 1.20G/s, 400G issued at 600M/s, 1.60T total
+\t100G resilvered, 25.00% done, 00:35:00 to go
+config:
+
+\tNAME             STATE     READ WRITE CKSUM
+\ttank             DEGRADED     0     0     0
+\t  mirror-0       DEGRADED     0     0     0
+\t    spare-0      DEGRADED     0     0     0
+\t      sda        FAULTED      0    24     0  too many errors
+\t      sdc        ONLINE       0     0     0  (resilvering)
+\t    sdb          ONLINE       0     0     3
+\t  mirror-1       ONLINE       0     0     0
+\t    sdd          ONLINE       0     0     0
+\t    sde          ONLINE       0     0     0
+\tlogs
+\t  mirror-2       ONLINE       0     0     0
+\t    nvme0n1p1    ONLINE       0     0     0
+\t    nvme1n1p1    ONLINE       0     0     0
+\tcache
+\t  nvme2n1        ONLINE       0     0     0
+\tspares
+\t  sdc            INUSE     currently in use
+\t  sdf            AVAIL
+
+errors: No known data errors
+
+  pool: boot-pool
+ state: ONLINE
+  scan: scrub repaired 0B in 00:00:09 with 0 errors on Sun Oct 13 03:45:10 2024
+config:
+
+\tNAME        STATE     READ WRITE CKSUM
+\tboot-pool   ONLINE       0     0     0
+\t  sdg3      ONLINE       0     0     0
+
+errors: No known data errors
+";
+
+    #[test]
+    fn parses_pools_and_multi_line_fields() {
+        let pools = parse_status(RESILVER).expect("status should parse");
+        assert_eq!(pools.len(), 2);
+
+        let tank = &pools[0];
+        assert_eq!(tank.name, "tank");
+        assert_eq!(tank.state, "DEGRADED");
+        assert_eq!(tank.status.as_deref(), Some("One or more devices is currently being resilvered.  The pool will continue to function, possibly in a degraded state."));
+        assert_eq!(tank.action.as_deref(), Some("Wait for the resilver to complete."));
+        assert_eq!(tank.errors.as_deref(), Some("No known data errors"));
+
+        let boot = &pools[1];
+        assert_eq!(boot.name, "boot-pool");
+        assert_eq!(boot.vdevs.len(), 1);
+        assert_eq!(boot.vdevs[0].name, "sdg3");
+    }
+
+    #[test]
+    fn builds_the_vdev_tree_with_classes() {
+        let pools = parse_status(RESILVER).expect("status should parse");
+        let vdevs = &pools[0].vdevs;
+
+        let names: Vec<(&str, &str)> = vdevs.iter().map(|vdev| (vdev.name.as_str(), vdev.class.as_str())).collect();
+        assert_eq!(names, vec![
+            ("mirror-0", "normal"), ("mirror-1", "normal"), ("mirror-2", "log"), ("nvme2n1", "cache"), ("sdc", "spare"), ("sdf", "spare")
+        ]);
+
+        let mirror = &vdevs[0];
+        assert_eq!(mirror.children.len(), 2);
+        let spare = &mirror.children[0];
+        assert_eq!(spare.name, "spare-0");
+        assert_eq!(spare.children.iter().map(|vdev| vdev.name.as_str()).collect::<Vec<_>>(), vec!["sda", "sdc"]);
+        assert_eq!(spare.children[0].state, "FAULTED");
+        assert_eq!(spare.children[0].write_errors, 24);
+        assert_eq!(mirror.children[1].checksum_errors, 3);
+
+        assert_eq!(vdevs[2].children.len(), 2);
+        assert_eq!(vdevs[4].state, "INUSE");
+        assert_eq!(vdevs[4].read_errors, 0);
+        assert_eq!(vdevs[5].state, "AVAIL");
+    }
+
+    #[test]
+    fn parses_resilver_progress() {
+        let pools = parse_status(RESILVER).expect("status should parse");
+
+        let scan = pools[0].scan.as_ref().expect("tank is resilvering");
+        assert_eq!(scan.function, "resilver");
+        assert_eq!(scan.state, "scanning");
+        assert_eq!(scan.progress_percent, Some(25.0));
+
+        let scan = pools[1].scan.as_ref().expect("boot-pool was scrubbed");
+        assert_eq!(scan.function, "scrub");
+        assert_eq!(scan.state, "finished");
+        assert_eq!(scan.progress_percent, Some(100.0));
+        assert_eq!(scan.errors, Some(0));
+    }
+
+    #[test]
+    fn ignores_scan_that_was_never_requested() {
+        assert!(parse_scan(&["none requested".to_string()]).is_none());
+
+        let canceled = parse_scan(&["scrub canceled on Sun Oct 13 00:30:00 2024".to_string()]).expect("canceled scrub");
+        assert_eq!(canceled.state, "canceled");
+    }
+}